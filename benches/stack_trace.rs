@@ -0,0 +1,100 @@
+#![allow(dead_code)]
+#![feature(coroutines)]
+#![feature(map_try_insert)]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+
+#[path = "../src/bin/arena_stack.rs"]
+#[allow(unused_attributes)]
+mod arena_stack;
+
+use arena_stack::{set_stack_trace_enabled, stack_traced, trace_channel, StackTrace};
+
+const FUTURES: usize = 1024;
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+}
+
+/// A future that is pending once before ready, so that the span will be stepped in and out.
+async fn work(i: usize) -> usize {
+    tokio::task::yield_now().await;
+    black_box(i)
+}
+
+fn bench_bare(c: &mut Criterion) {
+    c.bench_function("bare", |b| {
+        b.to_async(runtime()).iter(|| async {
+            for i in 0..FUTURES {
+                black_box(work(i).await);
+            }
+        })
+    });
+}
+
+/// The same as the futures compiled with the `stack-trace` feature off, but in a traced context,
+/// as the baseline of [`bench_stack_traced_disabled_at_runtime`].
+fn bench_bare_in_context(c: &mut Criterion) {
+    c.bench_function("bare_in_context", |b| {
+        b.to_async(runtime()).iter(|| {
+            let (trace_sender, _trace_receiver) = trace_channel();
+            stack_traced(
+                async {
+                    for i in 0..FUTURES {
+                        black_box(work(i).await);
+                    }
+                },
+                "bench",
+                trace_sender,
+            )
+        })
+    });
+}
+
+fn bench_stack_traced(c: &mut Criterion) {
+    c.bench_function("stack_traced", |b| {
+        b.to_async(runtime()).iter(|| {
+            let (trace_sender, _trace_receiver) = trace_channel();
+            stack_traced(
+                async {
+                    for i in 0..FUTURES {
+                        black_box(work(i).stack_trace("work").await);
+                    }
+                },
+                "bench",
+                trace_sender,
+            )
+        })
+    });
+}
+
+fn bench_stack_traced_disabled_at_runtime(c: &mut Criterion) {
+    set_stack_trace_enabled(false);
+    c.bench_function("stack_traced_disabled_at_runtime", |b| {
+        b.to_async(runtime()).iter(|| {
+            let (trace_sender, _trace_receiver) = trace_channel();
+            stack_traced(
+                async {
+                    for i in 0..FUTURES {
+                        black_box(work(i).stack_trace("work").await);
+                    }
+                },
+                "bench",
+                trace_sender,
+            )
+        })
+    });
+    set_stack_trace_enabled(true);
+}
+
+criterion_group!(
+    benches,
+    bench_bare,
+    bench_bare_in_context,
+    bench_stack_traced,
+    bench_stack_traced_disabled_at_runtime
+);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+#![feature(coroutines)]
+#![feature(map_try_insert)]
+// Both backends include `trace_context.rs` on their own.
+#![allow(clippy::duplicate_mod)]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+// Compare the span tree operations of the backends, through their `TraceContext` implementations.
+#[path = "../src/bin/arena_stack.rs"]
+#[allow(unused_attributes)]
+mod arena_stack;
+#[path = "../src/bin/stack.rs"]
+#[allow(unused_attributes)]
+mod stack;
+
+/// Each backend includes its own copy of the `TraceContext` trait, so use a macro instead of a
+/// generic function.
+macro_rules! bench_backend {
+    ($c:expr, $backend:ident :: $context:ident) => {{
+        use $backend::trace_context::TraceContext;
+
+        let mut group = $c.benchmark_group(stringify!($backend));
+
+        group.bench_function("push_pop", |b| {
+            let mut context = $backend::$context::new("root".into());
+            b.iter(|| {
+                context.push(black_box("span").into());
+                context.pop();
+            })
+        });
+
+        group.bench_function("step_in_out", |b| {
+            let mut context = $backend::$context::new("root".into());
+            let child = context.push("child".into());
+            context.step_out();
+            b.iter(|| {
+                context.step_in(black_box(&child));
+                context.step_out();
+            })
+        });
+
+        // Step in a child under another parent, which moves it to the current span.
+        group.bench_function("step_in_moved", |b| {
+            let mut context = $backend::$context::new("root".into());
+            let a = context.push("a".into());
+            let child = context.push("child".into());
+            context.step_out();
+            context.step_out();
+            let b_ = context.push("b".into());
+            context.step_out();
+            b.iter(|| {
+                for parent in [&b_, &a] {
+                    context.step_in(parent);
+                    context.step_in(black_box(&child));
+                    context.step_out();
+                    context.step_out();
+                }
+            })
+        });
+
+        group.finish();
+    }};
+}
+
+fn bench_arena(c: &mut Criterion) {
+    bench_backend!(c, arena_stack::ArenaTraceContext);
+}
+
+fn bench_rc(c: &mut Criterion) {
+    bench_backend!(c, stack::RcTraceContext);
+}
+
+criterion_group!(benches, bench_arena, bench_rc);
+criterion_main!(benches);
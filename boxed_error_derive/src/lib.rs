@@ -0,0 +1,537 @@
+//! Derive the boxed newtype of an error enum, which is hand-written as `MyError(MyBox<MyErrorInner>)`
+//! in `src/bin/error_thiserror.rs` and `Traced<E>` in `src/bin/error_provide.rs`, the context
+//! extension traits for constructing the variants from their sources, and the registration of the
+//! error codes declared on the variants.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Field, Fields, Ident, LitBool, LitInt, Token, Type,
+};
+
+/// Derive a pointer-sized newtype for the error, usually a `thiserror` enum:
+///
+/// ```ignore
+/// #[derive(thiserror::Error, Debug, BoxedError)]
+/// #[boxed_error(MyError, backtrace)]
+/// pub enum MyErrorInner { .. }
+/// ```
+///
+/// The newtype `MyError` boxes `MyErrorInner`, and implements:
+/// - `From<E>` for any `E: Into<MyErrorInner>`, so that `?` works on the sources,
+/// - `Display` by forwarding to the inner error, and `Debug` like `MyError(..)`,
+/// - `Error` with both `source` and `provide` forwarded. Note that `provide` must be forwarded
+///   explicitly, since `Box<T>` does not (rust-lang/rust#117432).
+///
+/// With the `backtrace` option, a `Backtrace` is also captured when the newtype is created, unless
+/// the inner error already provides one. It requires `#![feature(error_generic_member_access)]`.
+///
+/// With the `error_code` option, the codes declared on the variants with `#[code(..)]` are
+/// registered like [`derive_error_code`], and the code of the variant of the inner error is
+/// provided with `crate::error_code::provide` before forwarding, so that it takes precedence over
+/// the codes of the sources.
+#[proc_macro_derive(BoxedError, attributes(boxed_error, code))]
+pub fn derive_boxed_error(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match generate(&input) {
+        Ok(output) => output.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Derive a context extension trait for each variant of the error enum with a source and some
+/// other fields:
+///
+/// ```ignore
+/// #[derive(thiserror::Error, Debug, ErrorContext)]
+/// pub enum MyErrorInner {
+///     #[error("cannot parse int from `{from}`")]
+///     Parse {
+///         #[source]
+///         error: std::num::ParseIntError,
+///         from: String,
+///     },
+/// }
+/// ```
+///
+/// The trait is named after the variant, like `ParseResultExt`, and implemented on
+/// `Result<T, ParseIntError>` with:
+/// - `context(from: impl Into<String>)` that converts the error to the variant,
+/// - `with_context(|| from)` that evaluates the fields lazily only on error. With multiple fields,
+///   the closure returns a tuple of them in order.
+///
+/// The source is the field with `#[source]` or `#[from]`, or named `source`. A field of
+/// `Backtrace` is captured automatically. The result is converted to the newtype if
+/// `#[boxed_error(Name)]` is present, or the enum itself otherwise.
+///
+/// If multiple variants share the same source type, only import the trait to use, otherwise the
+/// methods will be ambiguous.
+#[proc_macro_derive(ErrorContext, attributes(boxed_error))]
+pub fn derive_error_context(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match generate_context(&input) {
+        Ok(output) => output.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Register the codes declared on the variants of the error enum to `crate::error_code`:
+///
+/// ```ignore
+/// #[derive(thiserror::Error, Debug, ErrorCode)]
+/// pub enum HummockErrorInner {
+///     #[error("Magic number mismatch")]
+///     #[code(1001, Storage, retryable = false)]
+///     MagicMismatch,
+/// }
+/// ```
+///
+/// The arguments are the code, the variant of `crate::error_code::Category`, and whether the
+/// operation may succeed if retried. The variants without a code are transparent, i.e., the code
+/// of the source error is provided instead, if any.
+///
+/// The enum itself does not provide the codes, since its `Error` impl is derived by `thiserror`.
+/// Call `crate::error_code::provide` in the wrapper, or use the `error_code` option of
+/// `BoxedError` instead of this one.
+#[proc_macro_derive(ErrorCode, attributes(code))]
+pub fn derive_error_code(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match generate_error_codes(&input) {
+        Ok(output) => output.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// The arguments in `#[boxed_error(Name, backtrace, error_code)]`.
+struct Args {
+    name: Ident,
+    backtrace: bool,
+    error_code: bool,
+}
+
+fn parse_args(input: &DeriveInput) -> syn::Result<Args> {
+    let attr = input
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("boxed_error"))
+        .ok_or_else(|| {
+            syn::Error::new_spanned(
+                &input.ident,
+                "expect `#[boxed_error(Name)]` for the name of the newtype",
+            )
+        })?;
+    let mut idents = attr
+        .parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?
+        .into_iter();
+
+    let name = idents
+        .next()
+        .ok_or_else(|| syn::Error::new_spanned(attr, "expect the name of the newtype"))?;
+    let mut backtrace = false;
+    let mut error_code = false;
+    for ident in idents {
+        if ident == "backtrace" {
+            backtrace = true;
+        } else if ident == "error_code" {
+            error_code = true;
+        } else {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "unknown option, expect `backtrace` or `error_code`",
+            ));
+        }
+    }
+
+    Ok(Args {
+        name,
+        backtrace,
+        error_code,
+    })
+}
+
+fn generate(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "generic errors are not supported",
+        ));
+    }
+
+    let Args {
+        name,
+        backtrace,
+        error_code,
+    } = parse_args(input)?;
+    let vis = &input.vis;
+    let inner = &input.ident;
+    let name_str = name.to_string();
+    let doc = format!("The boxed newtype of [`{inner}`], derived with `BoxedError`.");
+
+    // The boxed type, how to create it from the `inner` error, and the place of the inner error.
+    let (boxed_def, boxed, new_boxed, inner_place, into_inner) = if backtrace {
+        let boxed = format_ident!("{}Boxed", name);
+        let boxed_def = quote! {
+            #[doc(hidden)]
+            #vis struct #boxed {
+                inner: #inner,
+                backtrace: ::std::backtrace::Backtrace,
+            }
+        };
+        let new_boxed = quote! {{
+            // Only capture if the inner error does not provide one.
+            let backtrace =
+                if ::std::error::request_ref::<::std::backtrace::Backtrace>(&inner).is_some() {
+                    ::std::backtrace::Backtrace::disabled()
+                } else {
+                    ::std::backtrace::Backtrace::capture()
+                };
+            #boxed { inner, backtrace }
+        }};
+        let into_inner = quote!((*self.0).inner);
+        (
+            boxed_def,
+            quote!(#boxed),
+            new_boxed,
+            quote!(self.0.inner),
+            into_inner,
+        )
+    } else {
+        let into_inner = quote!(*self.0);
+        (
+            quote!(),
+            quote!(#inner),
+            quote!(inner),
+            quote!(*self.0),
+            into_inner,
+        )
+    };
+
+    let provide_backtrace = backtrace.then(|| {
+        quote! {
+            if let ::std::backtrace::BacktraceStatus::Captured = self.0.backtrace.status() {
+                request.provide_ref::<::std::backtrace::Backtrace>(&self.0.backtrace);
+            }
+        }
+    });
+
+    let provide_error_code = error_code.then(|| {
+        quote! {
+            crate::error_code::provide(&#inner_place, request);
+        }
+    });
+    let register_error_codes = if error_code {
+        generate_error_codes(input)?
+    } else if let Some(attr) = code_attrs(input).next() {
+        return Err(syn::Error::new_spanned(
+            attr,
+            "`#[code]` requires the `error_code` option of `#[boxed_error]`",
+        ));
+    } else {
+        quote!()
+    };
+
+    Ok(quote! {
+        #boxed_def
+        #register_error_codes
+
+        #[doc = #doc]
+        #vis struct #name(::std::boxed::Box<#boxed>);
+
+        impl #name {
+            /// Get a reference to the inner error.
+            pub fn inner(&self) -> &#inner {
+                &#inner_place
+            }
+
+            /// Unwrap into the inner error.
+            pub fn into_inner(self) -> #inner {
+                #into_inner
+            }
+        }
+
+        impl<E> ::std::convert::From<E> for #name
+        where
+            E: ::std::convert::Into<#inner>,
+        {
+            #[track_caller]
+            fn from(error: E) -> Self {
+                let inner: #inner = error.into();
+                Self(::std::boxed::Box::new(#new_boxed))
+            }
+        }
+
+        impl ::std::fmt::Display for #name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                ::std::fmt::Display::fmt(&#inner_place, f)
+            }
+        }
+
+        impl ::std::fmt::Debug for #name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.debug_tuple(#name_str).field(&#inner_place).finish()
+            }
+        }
+
+        impl ::std::error::Error for #name {
+            fn source(&self) -> ::std::option::Option<&(dyn ::std::error::Error + 'static)> {
+                ::std::error::Error::source(&#inner_place)
+            }
+
+            fn provide<'a>(&'a self, request: &mut ::std::error::Request<'a>) {
+                #provide_backtrace
+                #provide_error_code
+                ::std::error::Error::provide(&#inner_place, request)
+            }
+        }
+    })
+}
+
+/// The arguments in `#[code(1001, Storage, retryable = false)]`.
+struct CodeArgs {
+    code: LitInt,
+    category: Ident,
+    retryable: LitBool,
+}
+
+impl Parse for CodeArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let code = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let category = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let key: Ident = input.parse()?;
+        if key != "retryable" {
+            return Err(syn::Error::new_spanned(key, "expect `retryable = ..`"));
+        }
+        input.parse::<Token![=]>()?;
+        let retryable = input.parse()?;
+        input.parse::<Option<Token![,]>>()?;
+        Ok(Self {
+            code,
+            category,
+            retryable,
+        })
+    }
+}
+
+/// The `#[code]` attributes on the variants of the enum.
+fn code_attrs(input: &DeriveInput) -> impl Iterator<Item = &syn::Attribute> {
+    let variants = match &input.data {
+        Data::Enum(data) => Some(&data.variants),
+        _ => None,
+    };
+    variants
+        .into_iter()
+        .flatten()
+        .flat_map(|variant| &variant.attrs)
+        .filter(|attr| attr.path().is_ident("code"))
+}
+
+fn generate_error_codes(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "only enums are supported",
+        ));
+    };
+    let inner = &input.ident;
+
+    let mut codes = Vec::new();
+    for variant in &data.variants {
+        let Some(attr) = variant
+            .attrs
+            .iter()
+            .find(|attr| attr.path().is_ident("code"))
+        else {
+            continue;
+        };
+        let CodeArgs {
+            code,
+            category,
+            retryable,
+        } = attr.parse_args()?;
+        let variant_ident = &variant.ident;
+        let name = format!("{inner}::{variant_ident}");
+
+        codes.push(quote! {
+            ::inventory::submit! {
+                crate::error_code::ErrorCode {
+                    code: #code,
+                    category: crate::error_code::Category::#category,
+                    retryable: #retryable,
+                    name: #name,
+                    matches: |error| ::std::matches!(
+                        error.downcast_ref::<#inner>(),
+                        ::std::option::Option::Some(#inner::#variant_ident { .. })
+                    ),
+                }
+            }
+        });
+    }
+
+    Ok(quote!(#(#codes)*))
+}
+
+/// Whether the field is the source of the variant, in the same way as `thiserror`.
+fn is_source(field: &Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("source") || attr.path().is_ident("from"))
+        || field.ident.as_ref().is_some_and(|ident| ident == "source")
+}
+
+fn is_backtrace(field: &Field) -> bool {
+    match &field.ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Backtrace"),
+        _ => false,
+    }
+}
+
+fn generate_context(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "only enums are supported",
+        ));
+    };
+    let vis = &input.vis;
+    let inner = &input.ident;
+    let output = if input
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("boxed_error"))
+    {
+        let name = parse_args(input)?.name;
+        quote!(#name)
+    } else {
+        quote!(#inner)
+    };
+
+    let mut traits = Vec::new();
+    for variant in &data.variants {
+        let fields = match &variant.fields {
+            Fields::Named(fields) => &fields.named,
+            Fields::Unnamed(fields) => &fields.unnamed,
+            Fields::Unit => continue,
+        };
+        let Some(source) = fields.iter().find(|field| is_source(field)) else {
+            continue;
+        };
+        // The fields to be provided as the context, with the names of the parameters.
+        let context_fields: Vec<_> = fields
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| !is_source(field) && !is_backtrace(field))
+            .map(|(i, field)| {
+                let param = match &field.ident {
+                    Some(ident) => ident.clone(),
+                    None => format_ident!("field_{}", i),
+                };
+                (param, &field.ty)
+            })
+            .collect();
+        if context_fields.is_empty() {
+            continue;
+        }
+
+        // Construct the variant from `__source` and the parameters.
+        let values = fields.iter().enumerate().map(|(i, field)| {
+            if is_source(field) {
+                quote!(__source)
+            } else if is_backtrace(field) {
+                quote!(::std::backtrace::Backtrace::capture())
+            } else {
+                let param = match &field.ident {
+                    Some(ident) => ident.clone(),
+                    None => format_ident!("field_{}", i),
+                };
+                quote!(::std::convert::Into::into(#param))
+            }
+        });
+        let variant_ident = &variant.ident;
+        let construct = match &variant.fields {
+            Fields::Named(_) => {
+                let idents = fields.iter().map(|field| &field.ident);
+                quote!(#inner::#variant_ident { #(#idents: #values),* })
+            }
+            _ => quote!(#inner::#variant_ident(#(#values),*)),
+        };
+
+        let params = context_fields
+            .iter()
+            .map(|(param, _)| param)
+            .collect::<Vec<_>>();
+        let tys = context_fields.iter().map(|(_, ty)| ty).collect::<Vec<_>>();
+        let generics = (0..params.len())
+            .map(|i| format_ident!("C{}", i))
+            .collect::<Vec<_>>();
+        let (lazy_pattern, lazy_output) = if params.len() == 1 {
+            (quote!(#(#params)*), quote!(#(#generics)*))
+        } else {
+            (quote!((#(#params),*)), quote!((#(#generics),*)))
+        };
+        let generic_bounds = generics
+            .iter()
+            .zip(&tys)
+            .map(|(generic, ty)| quote!(#generic: ::std::convert::Into<#ty>))
+            .collect::<Vec<_>>();
+
+        let source_ty = &source.ty;
+        let trait_name = format_ident!("{}ResultExt", variant_ident);
+        let doc = format!(
+            "Extension on results of the source to construct [`{inner}::{variant_ident}`], \
+             derived with `ErrorContext`."
+        );
+
+        traits.push(quote! {
+            #[doc = #doc]
+            #vis trait #trait_name<T> {
+                /// Convert the error with the given context.
+                fn context(
+                    self,
+                    #(#params: impl ::std::convert::Into<#tys>),*
+                ) -> ::std::result::Result<T, #output>;
+
+                /// Convert the error with the context evaluated lazily.
+                fn with_context<#(#generics),*>(
+                    self,
+                    f: impl ::std::ops::FnOnce() -> #lazy_output,
+                ) -> ::std::result::Result<T, #output>
+                where
+                    #(#generic_bounds),*;
+            }
+
+            impl<T> #trait_name<T> for ::std::result::Result<T, #source_ty> {
+                fn context(
+                    self,
+                    #(#params: impl ::std::convert::Into<#tys>),*
+                ) -> ::std::result::Result<T, #output> {
+                    self.map_err(|__source| #construct.into())
+                }
+
+                fn with_context<#(#generics),*>(
+                    self,
+                    f: impl ::std::ops::FnOnce() -> #lazy_output,
+                ) -> ::std::result::Result<T, #output>
+                where
+                    #(#generic_bounds),*
+                {
+                    self.map_err(|__source| {
+                        let #lazy_pattern = f();
+                        #construct.into()
+                    })
+                }
+            }
+        });
+    }
+
+    Ok(quote!(#(#traits)*))
+}
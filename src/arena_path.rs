@@ -0,0 +1,26 @@
+//! The path of the spans being polled in the `TRACE_CONTEXT` of `arena_stack`, for the errors to
+//! capture where they're created without including the whole backend. The backend registers the
+//! accessor walking its context, so the path is always `None` in the bins without it.
+
+use std::sync::OnceLock;
+
+/// Get the spans from the root to the innermost one being polled in the current task, or `None`
+/// if not in a traced context.
+pub type CurrentSpanPath = fn() -> Option<Vec<String>>;
+
+static CURRENT_SPAN_PATH: OnceLock<CurrentSpanPath> = OnceLock::new();
+
+/// Register the accessor of the backend. Only the first one is kept.
+#[allow(dead_code)] // Only called by the backend.
+pub fn register(current_span_path: CurrentSpanPath) {
+    CURRENT_SPAN_PATH.get_or_init(|| current_span_path);
+}
+
+/// Get the span path of the current task with the registered accessor. Returns `None` if there's
+/// no backend registered or not in a traced context.
+#[allow(dead_code)] // Only called by the errors.
+pub fn current_span_path() -> Option<Vec<String>> {
+    CURRENT_SPAN_PATH
+        .get()
+        .and_then(|current_span_path| current_span_path())
+}
@@ -0,0 +1,202 @@
+//! Pretty-printing of the captured [`Backtrace`] in the error reports, which is mostly the frames
+//! of the async runtime otherwise.
+
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::fmt;
+
+/// A frame parsed from the captured [`Backtrace`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub index: usize,
+    pub symbol: String,
+    /// The `file:line:column` of the frame, if resolved.
+    pub location: Option<String>,
+}
+
+/// Parse the frames from the `Display` output of a [`Backtrace`], which looks like:
+///
+/// ```text
+///    0: std::backtrace::Backtrace::capture
+///              at /rustc/.../library/std/src/backtrace.rs:296:9
+///    1: error_thiserror::work
+///              at ./src/bin/error_thiserror.rs:42:5
+/// ```
+pub fn parse_frames(text: &str) -> Vec<Frame> {
+    let mut frames: Vec<Frame> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if let Some(location) = line.strip_prefix("at ") {
+            if let Some(frame) = frames.last_mut() {
+                frame.location = Some(location.to_owned());
+            }
+        } else if let Some((index, symbol)) = line.split_once(": ") {
+            if let Ok(index) = index.parse() {
+                frames.push(Frame {
+                    index,
+                    symbol: symbol.to_owned(),
+                    location: None,
+                });
+            }
+        }
+    }
+    frames
+}
+
+/// Pretty-prints a [`Backtrace`] with the runtime and std internals collapsed, the frames of this
+/// crate highlighted with `>`, and the frames above the `?` site trimmed.
+///
+/// A frame is matched against the lists by the prefix of its symbol, where the allow list takes
+/// precedence over the deny list.
+#[derive(Debug, Clone)]
+pub struct BacktraceFormatter {
+    local: Vec<String>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    /// The frames up to the last one containing any of these are trimmed, which are the
+    /// internals of capturing the backtrace and converting the error.
+    trim_above: Vec<String>,
+}
+
+impl Default for BacktraceFormatter {
+    fn default() -> Self {
+        const DENY: &[&str] = &[
+            "std::",
+            "core::",
+            "alloc::",
+            "tokio::",
+            "futures::",
+            "futures_core::",
+            "futures_util::",
+            "<std::",
+            "<core::",
+            "<alloc::",
+            "<tokio::",
+            "<futures",
+            "__rust",
+            "__libc_start",
+            "_start",
+            "<unknown>",
+        ];
+
+        Self {
+            local: vec![format!("{}::", env!("CARGO_CRATE_NAME"))],
+            allow: Vec::new(),
+            deny: DENY.iter().map(|s| s.to_string()).collect(),
+            trim_above: vec![
+                "from_residual".to_owned(),
+                " as core::convert::From<".to_owned(),
+                " as core::convert::Into<".to_owned(),
+            ],
+        }
+    }
+}
+
+impl BacktraceFormatter {
+    /// Show the frames with the given prefix, even if it's denied.
+    pub fn allow(mut self, prefix: impl Into<String>) -> Self {
+        self.allow.push(prefix.into());
+        self
+    }
+
+    /// Collapse the frames with the given prefix.
+    pub fn deny(mut self, prefix: impl Into<String>) -> Self {
+        self.deny.push(prefix.into());
+        self
+    }
+
+    /// Format the backtrace. It's printed as-is if not captured.
+    pub fn display<'a>(&'a self, backtrace: &Backtrace) -> FormattedBacktrace<'a> {
+        let text = backtrace.to_string();
+        let frames = match backtrace.status() {
+            BacktraceStatus::Captured => parse_frames(&text),
+            _ => Vec::new(),
+        };
+        FormattedBacktrace {
+            formatter: self,
+            text,
+            frames,
+        }
+    }
+
+    /// Format the `Display` output of a backtrace, for testing with a fixed one.
+    #[cfg(test)]
+    #[allow(dead_code)] // Not every bin including this module tests the formatting.
+    pub fn display_text<'a>(&'a self, text: &str) -> FormattedBacktrace<'a> {
+        FormattedBacktrace {
+            formatter: self,
+            text: text.to_owned(),
+            frames: parse_frames(text),
+        }
+    }
+
+    fn matches(list: &[String], frame: &Frame) -> bool {
+        list.iter().any(|prefix| frame.symbol.starts_with(prefix))
+    }
+
+    fn is_hidden(&self, frame: &Frame) -> bool {
+        Self::matches(&self.deny, frame) && !Self::matches(&self.allow, frame)
+    }
+
+    /// Get the frames below the `?` site, i.e., with the ones for capturing the backtrace trimmed.
+    fn trimmed<'f>(&self, frames: &'f [Frame]) -> &'f [Frame] {
+        let site = frames.iter().rposition(|frame| {
+            self.trim_above
+                .iter()
+                .any(|pattern| frame.symbol.contains(pattern))
+        });
+        match site {
+            Some(i) => &frames[i + 1..],
+            // Not converted, trim the ones for capturing only.
+            None => {
+                let start = frames
+                    .iter()
+                    .position(|frame| !frame.symbol.starts_with("std::backtrace"))
+                    .unwrap_or(frames.len());
+                &frames[start..]
+            }
+        }
+    }
+}
+
+/// The backtrace formatted with [`BacktraceFormatter`].
+pub struct FormattedBacktrace<'a> {
+    formatter: &'a BacktraceFormatter,
+    text: String,
+    frames: Vec<Frame>,
+}
+
+impl fmt::Display for FormattedBacktrace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.frames.is_empty() {
+            return f.write_str(&self.text);
+        }
+
+        let mut hidden = 0;
+        let flush_hidden = |f: &mut fmt::Formatter<'_>, hidden: &mut usize| {
+            if *hidden > 0 {
+                let s = if *hidden == 1 { "" } else { "s" };
+                writeln!(f, "      ... {} frame{} hidden", hidden, s)?;
+                *hidden = 0;
+            }
+            Ok(())
+        };
+
+        for frame in self.formatter.trimmed(&self.frames) {
+            if self.formatter.is_hidden(frame) {
+                hidden += 1;
+                continue;
+            }
+            flush_hidden(f, &mut hidden)?;
+
+            let mark = if BacktraceFormatter::matches(&self.formatter.local, frame) {
+                '>'
+            } else {
+                ' '
+            };
+            writeln!(f, "{}{:>3}: {}", mark, frame.index, frame.symbol)?;
+            if let Some(location) = &frame.location {
+                writeln!(f, "             at {}", location)?;
+            }
+        }
+        flush_hidden(f, &mut hidden)
+    }
+}
@@ -49,6 +49,22 @@ macro_rules! def_anyhow_newtype {
             pub fn into_inner(self) -> ::anyhow::Error {
                 self.0
             }
+
+            /// Find the first error of type `T` in the chain of sources.
+            pub fn find_source<T>(&self) -> Option<&T>
+            where
+                T: std::error::Error + 'static,
+            {
+                self.0.chain().find_map(|error| error.downcast_ref::<T>())
+            }
+
+            /// Check whether there's an error of type `T` in the chain of sources.
+            pub fn is<T>(&self) -> bool
+            where
+                T: std::error::Error + 'static,
+            {
+                self.find_source::<T>().is_some()
+            }
         }
 
         impl std::ops::Deref for $name {
@@ -137,3 +153,45 @@ fn main() {
     let o = OuterError::Inner(e);
     println!("{}", o.as_report());
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::ParseIntError;
+
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_find_source() {
+        let error: MyError = "foo"
+            .parse::<i32>()
+            .context("bad input")
+            .unwrap_err()
+            .into();
+        assert!(error.is::<ParseIntError>());
+        assert!(error.find_source::<std::io::Error>().is_none());
+
+        // With context attached again on the newtype.
+        let error: MyError = Err::<(), _>(error).context("outer").unwrap_err().into();
+        assert!(error.is::<ParseIntError>());
+    }
+    #[derive(thiserror::Error, Debug)]
+    #[error("failed to read config")]
+    struct ReadConfigError(#[source] std::io::Error);
+
+    #[test]
+    fn test_find_source_in_context() {
+        // The source of an error wrapped in a context.
+        let error: MyError = Err::<(), _>(ReadConfigError(std::io::ErrorKind::NotFound.into()))
+            .context("failed to start")
+            .unwrap_err()
+            .into();
+        assert!(error.is::<ReadConfigError>());
+        assert_eq!(
+            error.find_source::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert!(!error.is::<ParseIntError>());
+    }
+}
@@ -86,6 +86,23 @@ macro_rules! def_anyhow_newtype {
             pub fn into_inner(self) -> ::anyhow::Error {
                 self.0
             }
+
+            /// Find the first error of type `T` in the chain of sources, including the
+            /// source errors with default context converted `From`.
+            pub fn find_source<T>(&self) -> Option<&T>
+            where
+                T: std::error::Error + 'static,
+            {
+                self.0.chain().find_map(|error| error.downcast_ref::<T>())
+            }
+
+            /// Check whether there's an error of type `T` in the chain of sources.
+            pub fn is<T>(&self) -> bool
+            where
+                T: std::error::Error + 'static,
+            {
+                self.find_source::<T>().is_some()
+            }
         }
 
         $(
@@ -123,3 +140,53 @@ fn main() {
 
     println!("{}", o.as_report());
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_find_source() {
+        // Converted with the default context.
+        let error: MyError = "foo".parse::<i32>().unwrap_err().into();
+        assert!(error.is::<ParseIntError>());
+        assert_eq!(
+            error.find_source::<ParseIntError>().unwrap().to_string(),
+            "invalid digit found in string"
+        );
+        assert!(!error.is::<std::io::Error>());
+
+        // Converted with explicit context.
+        let error: MyError = "".parse::<i32>().context("empty").unwrap_err().into();
+        assert!(error.is::<ParseIntError>());
+
+        let error: MyError = anyhow!("233").into();
+        assert!(!error.is::<ParseIntError>());
+    }
+    #[derive(thiserror::Error, Debug)]
+    #[error("failed to read config")]
+    struct ReadConfigError(#[source] std::io::Error);
+
+    #[test]
+    fn test_find_source_in_context() {
+        // The source of an error wrapped in a context.
+        let error: MyError = Err::<(), _>(ReadConfigError(std::io::ErrorKind::NotFound.into()))
+            .context("failed to start")
+            .unwrap_err()
+            .into();
+        assert!(error.is::<ReadConfigError>());
+        assert_eq!(
+            error.find_source::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert!(!error.is::<ParseIntError>());
+
+        // Converted with the default context, then wrapped in another context.
+        let error: MyError = "foo".parse::<i32>().unwrap_err().into();
+        let error: MyError = Err::<(), _>(error).context("outer").unwrap_err().into();
+        assert!(error.is::<ParseIntError>());
+        assert_eq!(error.to_string(), "outer");
+    }
+}
@@ -14,34 +14,52 @@
 
 #![feature(coroutines)]
 #![feature(map_try_insert)]
-// FIXME: This is a false-positive clippy test, remove this while bumping toolchain.
-// https://github.com/tokio-rs/tokio/issues/4836
-// https://github.com/rust-lang/rust-clippy/issues/8493
-#![expect(clippy::declare_interior_mutable_const)]
-
-use std::borrow::Cow;
-use std::cell::{RefCell, RefMut};
-use std::collections::HashMap;
+
+#[macro_use]
+#[path = "../trace_context.rs"]
+pub mod trace_context;
+#[path = "../arena_path.rs"]
+pub mod arena_path;
+
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug, Write};
 use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::task::Poll;
 use std::time::{Duration, Instant};
 
+#[cfg(feature = "stack-trace")]
 use futures::future::Fuse;
-use futures::{Future, FutureExt};
+#[cfg(feature = "stack-trace")]
+use futures::FutureExt;
+use futures::{Future, Stream};
 use indextree::{Arena, NodeId};
 use itertools::Itertools;
 use pin_project::{pin_project, pinned_drop};
-use tokio::sync::watch;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMicroSeconds};
+use tokio::sync::{watch, Notify};
 
-pub type SpanValue = Cow<'static, str>;
+pub use trace_context::SpanValue;
+use trace_context::TraceContext;
 
 /// The report of a stack trace.
 #[derive(Debug, Clone)]
 pub struct StackTraceReport {
     pub report: String,
+    /// The structured span tree, `None` if not reported yet.
+    pub tree: Option<SpanTreeNode>,
+    /// The detached subtrees, whose futures are still alive but not polled under any span now.
+    pub detached: Vec<SpanTreeNode>,
+    /// The statistics of the completed spans, keyed by the span. See [`TraceConfig::max_stats`].
+    pub stats: BTreeMap<String, SpanStats>,
+    /// The resources held by the task with [`hold_resource`], sorted. The ones held by the tasks
+    /// spawned with [`spawn_stack_traced`] are included, as they're reported as part of this task.
+    pub held: Vec<String>,
+    pub arena: ArenaStats,
     pub capture_time: Instant,
 }
 
@@ -49,11 +67,89 @@ impl Default for StackTraceReport {
     fn default() -> Self {
         Self {
             report: "<not reported>".to_string(),
+            tree: None,
+            detached: Vec::new(),
+            stats: BTreeMap::new(),
+            held: Vec::new(),
+            arena: ArenaStats::default(),
             capture_time: Instant::now(),
         }
     }
 }
 
+impl StackTraceReport {
+    /// Export the span tree of this report as JSON, so that tools can diff and filter the traces
+    /// instead of parsing the rendered text.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "captured_ago_us": self.capture_time.elapsed().as_micros() as u64,
+            "tree": self.tree,
+            "detached": self.detached,
+            "stats": self.stats,
+            "held": self.held,
+            "arena": self.arena,
+        })
+    }
+
+    /// Get a copy of this report with only the spans pending for at least `min_elapsed`, and at
+    /// most `max_depth` levels below the root. The text report is rendered again from the
+    /// filtered tree.
+    pub fn filtered(&self, min_elapsed: Duration, max_depth: Option<usize>) -> Self {
+        let Some(tree) = &self.tree else {
+            return self.clone();
+        };
+        let tree = tree.filtered(min_elapsed, max_depth);
+        let detached = self
+            .detached
+            .iter()
+            .filter(|node| node.elapsed >= min_elapsed)
+            .map(|node| node.filtered(min_elapsed, max_depth))
+            .collect_vec();
+
+        self.with_trees(tree, detached)
+    }
+
+    /// Get a copy of this report with only the spans whose field `key` is rendered as `value`, and
+    /// their ancestors. The root is always kept.
+    pub fn filtered_by_field(&self, key: &str, value: &str) -> Self {
+        let Some(tree) = &self.tree else {
+            return self.clone();
+        };
+        let tree = tree
+            .filtered_by_field(key, value)
+            .unwrap_or_else(|| tree.filtered(Duration::ZERO, Some(0)));
+        let detached = self
+            .detached
+            .iter()
+            .filter_map(|node| node.filtered_by_field(key, value))
+            .collect_vec();
+
+        self.with_trees(tree, detached)
+    }
+
+    /// Get a copy of this report with the given trees, and the text report rendered from them.
+    fn with_trees(&self, tree: SpanTreeNode, detached: Vec<SpanTreeNode>) -> Self {
+        let mut report = tree.to_string();
+        for node in &detached {
+            writeln!(report, "[Detached {}]", node.id).unwrap();
+            for line in node.to_string().lines() {
+                writeln!(report, "  {}", line).unwrap();
+            }
+        }
+
+        Self {
+            report,
+            tree: Some(tree),
+            detached,
+            stats: self.stats.clone(),
+            held: self.held.clone(),
+            arena: self.arena.clone(),
+            capture_time: self.capture_time,
+        }
+    }
+}
+
+/// Render the text report, followed by the statistics of the completed spans if any.
 impl std::fmt::Display for StackTraceReport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -61,8 +157,238 @@ impl std::fmt::Display for StackTraceReport {
             "[captured {:?} ago]\n{}",
             self.capture_time.elapsed(),
             self.report
-        )
+        )?;
+
+        if !self.stats.is_empty() {
+            writeln!(f, "[stats]")?;
+            for (span, stats) in &self.stats {
+                writeln!(
+                    f,
+                    "  {}: count {}, mean {:?}, max {:?}",
+                    span,
+                    stats.count,
+                    stats.mean(),
+                    stats.max
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The statistics of the completed spans with the same span, so that we can tell which awaits are
+/// usually slow.
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpanStats {
+    pub count: u64,
+    #[serde_as(as = "DurationMicroSeconds<u64>")]
+    #[serde(rename = "total_us")]
+    pub total: Duration,
+    #[serde_as(as = "DurationMicroSeconds<u64>")]
+    #[serde(rename = "max_us")]
+    pub max: Duration,
+    /// The count of spans completed within each of [`SpanStats::BUCKETS`] but not the previous
+    /// one. The last one is for the spans longer than all of the buckets.
+    pub histogram: [u64; SpanStats::BUCKETS.len() + 1],
+}
+
+impl SpanStats {
+    /// The upper bounds of the buckets in the histogram.
+    pub const BUCKETS: [Duration; 8] = [
+        Duration::from_millis(1),
+        Duration::from_millis(10),
+        Duration::from_millis(50),
+        Duration::from_millis(100),
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+        Duration::from_secs(5),
+        Duration::from_secs(10),
+    ];
+
+    /// Record a completed span with the given elapsed time.
+    fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+        let bucket = Self::BUCKETS.partition_point(|&bound| bound < elapsed);
+        self.histogram[bucket] += 1;
+    }
+
+    /// The key of the statistics for the spans beyond [`TraceConfig::max_stats`].
+    pub const OTHERS: &'static str = "<others>";
+
+    /// The mean elapsed time of the completed spans.
+    pub fn mean(&self) -> Duration {
+        self.total
+            .as_nanos()
+            .checked_div(self.count as u128)
+            .map_or(Duration::ZERO, |nanos| Duration::from_nanos(nanos as u64))
+    }
+}
+
+/// The memory usage of the span arena of a context.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArenaStats {
+    /// The count of alive span nodes.
+    pub active_nodes: usize,
+    /// The count of slots in the arena, including the freed ones to be reused.
+    pub slots: usize,
+    /// The count of slots allocated for the arena.
+    pub capacity: usize,
+    /// The estimated bytes allocated for the arena.
+    pub bytes: usize,
+    /// The count of spans collapsed for [`TraceConfig::max_nodes`], whose futures are still
+    /// alive.
+    pub collapsed: usize,
+}
+
+/// A span that has been pending for longer than [`TraceConfig::slow_threshold`].
+#[derive(Debug, Clone)]
+pub struct SlowSpan {
+    /// The spans from the root to the slow span.
+    pub path: Vec<SpanValue>,
+    pub elapsed: Duration,
+}
+
+impl std::fmt::Display for SlowSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} [{:?}]", self.path.iter().join(" -> "), self.elapsed)
+    }
+}
+
+pub type SlowSpanCallback = Arc<dyn Fn(&SlowSpan) + Send + Sync>;
+
+/// Configuration for [`stack_traced_with_config`].
+#[derive(Clone)]
+pub struct TraceConfig {
+    /// If set, the report will be captured every such interval besides the requested ones.
+    /// Otherwise, it's only captured on request with [`TraceReceiver::request`].
+    pub report_interval: Option<Duration>,
+    /// Spans pending for longer than this will be marked with `!!!` in the report, and reported
+    /// by the watchdog if it's enabled with `on_slow_span` or `warn_slow_spans`.
+    pub slow_threshold: Duration,
+    /// Called once for each span crossing the `slow_threshold`.
+    pub on_slow_span: Option<SlowSpanCallback>,
+    /// If set, a warning will be logged once for each span crossing the `slow_threshold`, unless
+    /// `on_slow_span` is set. If neither is set, the watchdog won't be started at all, so that
+    /// there's no periodic work in the traced task.
+    pub warn_slow_spans: bool,
+    /// If set, a `tracing` span named `stack_trace` will be emitted for each span on first poll,
+    /// and closed once the future is ready or dropped. So the same instrumentation shows up in the
+    /// output of `tracing_forest::ForestLayer` and any other subscriber.
+    pub tracing_spans: bool,
+    /// If set, the oldest leaf spans will be collapsed into a `... N more` placeholder under their
+    /// parents once there're so many span nodes, so that the size of the tree and the report is
+    /// bounded even if there're lots of pending futures. The collapsed spans are kept aside with
+    /// their state, and pushed back once polled again.
+    ///
+    /// The spans on the path to the current span are never collapsed, so the count of nodes in
+    /// the tree is bounded by the larger of this and the depth of that path. Note that if more
+    /// spans than this are polled in turn, e.g., under `join_all`, they will be collapsed and
+    /// pushed back in turn.
+    pub max_nodes: Option<usize>,
+    /// The maximum count of distinct spans to keep the statistics for. The spans completed after
+    /// that are recorded together as [`SpanStats::OTHERS`], so that the statistics are bounded
+    /// even if the spans are built with `format!`.
+    pub max_stats: usize,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            report_interval: None,
+            slow_threshold: Duration::from_secs(1),
+            on_slow_span: None,
+            warn_slow_spans: false,
+            tracing_spans: false,
+            max_nodes: None,
+            max_stats: 1024,
+        }
+    }
+}
+
+impl Debug for TraceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraceConfig")
+            .field("report_interval", &self.report_interval)
+            .field("slow_threshold", &self.slow_threshold)
+            .field("warn_slow_spans", &self.warn_slow_spans)
+            .field("tracing_spans", &self.tracing_spans)
+            .field("max_nodes", &self.max_nodes)
+            .field("max_stats", &self.max_stats)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The value of a field on a span, recorded with [`StackTrace::stack_trace_with_fields`] or
+/// [`record_field`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FieldValue {
+    U64(u64),
+    I64(i64),
+    Bool(bool),
+    Str(SpanValue),
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::U64(v) => write!(f, "{}", v),
+            Self::I64(v) => write!(f, "{}", v),
+            Self::Bool(v) => write!(f, "{}", v),
+            Self::Str(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+macro_rules! impl_from_for_field_value {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$ty> for FieldValue {
+                fn from(v: $ty) -> Self {
+                    Self::$variant(v.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_for_field_value! {
+    u64 => U64,
+    u32 => U64,
+    i64 => I64,
+    i32 => I64,
+    bool => Bool,
+    &'static str => Str,
+    String => Str,
+    SpanValue => Str,
+}
+
+impl From<usize> for FieldValue {
+    fn from(v: usize) -> Self {
+        Self::U64(v as u64)
+    }
+}
+
+/// The fields on a span, sorted by the key.
+pub type Fields = BTreeMap<&'static str, FieldValue>;
+
+/// Format the fields as ` {key=value, ..}`, or nothing if empty.
+fn fmt_fields<'a, K: std::fmt::Display + 'a>(
+    f: &mut std::fmt::Formatter<'_>,
+    fields: impl IntoIterator<Item = (K, &'a FieldValue)>,
+) -> std::fmt::Result {
+    let mut fields = fields.into_iter().peekable();
+    if fields.peek().is_some() {
+        write!(
+            f,
+            " {{{}}}",
+            fields.format_with(", ", |(k, v), write| write(&format_args!("{}={}", k, v)))
+        )?;
     }
+    Ok(())
 }
 
 /// Node in the span tree.
@@ -71,45 +397,281 @@ struct SpanNode {
     span: SpanValue,
     // TODO: may use a more efficient timing mechanism
     start_time: Instant,
+    /// Whether this span has been reported as slow by the watchdog.
+    slow_reported: bool,
+    /// The count of items yielded, only for the spans of streams.
+    items: Option<usize>,
+    fields: Fields,
+    /// The `tracing` span for this node, if [`TraceConfig::tracing_spans`] is set. It's closed
+    /// once this node is removed.
+    tracing_span: Option<tracing::Span>,
+    /// The count of the children collapsed for [`TraceConfig::max_nodes`].
+    collapsed: usize,
+}
+
+/// Create a `tracing` span for the given span under the `parent`, or the current `tracing` span if
+/// not specified.
+fn new_tracing_span(span: &str, parent: Option<&tracing::Span>) -> tracing::Span {
+    match parent {
+        Some(parent) => {
+            tracing::info_span!(target: "stack_trace", parent: parent, "stack_trace", span = %span)
+        }
+        None => tracing::info_span!(target: "stack_trace", "stack_trace", span = %span),
+    }
 }
 
 impl SpanNode {
     /// Create a new node with the given value.
     fn new(span: SpanValue) -> Self {
+        Self::with_start_time(span, Instant::now())
+    }
+
+    /// Create a new node with the given value and start time, used for migrating a span from
+    /// another context.
+    fn with_start_time(span: SpanValue, start_time: Instant) -> Self {
         Self {
             span,
-            start_time: Instant::now(),
+            start_time,
+            slow_reported: false,
+            items: None,
+            fields: Fields::new(),
+            tracing_span: None,
+            collapsed: 0,
+        }
+    }
+
+    /// Set the fields of this node.
+    #[cfg(feature = "stack-trace")]
+    fn with_fields(self, fields: Fields) -> Self {
+        Self { fields, ..self }
+    }
+}
+
+/// Serializable snapshot of a [`SpanNode`] and its children, mirroring the span arena.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpanTreeNode {
+    /// The index of this node in the arena.
+    pub id: usize,
+    /// The index of the parent node in the arena, `None` for the root.
+    pub parent_id: Option<usize>,
+    pub span: String,
+    pub depth: usize,
+    #[serde_as(as = "DurationMicroSeconds<u64>")]
+    #[serde(rename = "elapsed_us")]
+    pub elapsed: Duration,
+    /// Whether the span is pending for at least [`TraceConfig::slow_threshold`], marked with
+    /// `!!!` in the text report. Always `false` for the root.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub slow: bool,
+    /// The count of items yielded, only for the spans of streams.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<usize>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, FieldValue>,
+    pub children: Vec<SpanTreeNode>,
+    /// The count of the children collapsed for [`TraceConfig::max_nodes`].
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub collapsed: usize,
+    /// The root nodes of the tasks spawned under this span with [`spawn_stack_traced`]. Note
+    /// that the ids in these trees are local to the arena of the spawned task.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spawned: Vec<SpanTreeNode>,
+}
+
+impl SpanTreeNode {
+    /// Get a copy of this tree with only the spans pending for at least `min_elapsed`, and at most
+    /// `max_depth` levels below this node. This node itself is always kept.
+    pub fn filtered(&self, min_elapsed: Duration, max_depth: Option<usize>) -> Self {
+        let filter = |nodes: &[SpanTreeNode]| match max_depth {
+            Some(0) => Vec::new(),
+            _ => nodes
+                .iter()
+                .filter(|node| node.elapsed >= min_elapsed)
+                .map(|node| node.filtered(min_elapsed, max_depth.map(|d| d - 1)))
+                .collect(),
+        };
+
+        Self {
+            id: self.id,
+            parent_id: self.parent_id,
+            span: self.span.clone(),
+            depth: self.depth,
+            elapsed: self.elapsed,
+            slow: self.slow,
+            items: self.items,
+            fields: self.fields.clone(),
+            children: filter(&self.children),
+            collapsed: self.collapsed,
+            spawned: filter(&self.spawned),
+        }
+    }
+
+    /// Get a copy of this tree with only the spans whose field `key` is rendered as `value`, and
+    /// their ancestors. Returns `None` if there's no such span.
+    pub fn filtered_by_field(&self, key: &str, value: &str) -> Option<Self> {
+        let filter = |nodes: &[SpanTreeNode]| {
+            nodes
+                .iter()
+                .filter_map(|node| node.filtered_by_field(key, value))
+                .collect_vec()
+        };
+        let children = filter(&self.children);
+        let spawned = filter(&self.spawned);
+        let matched = self.fields.get(key).is_some_and(|v| v.to_string() == value);
+
+        (matched || !children.is_empty() || !spawned.is_empty()).then(|| Self {
+            id: self.id,
+            parent_id: self.parent_id,
+            span: self.span.clone(),
+            depth: self.depth,
+            elapsed: self.elapsed,
+            slow: self.slow,
+            items: self.items,
+            fields: self.fields.clone(),
+            children,
+            collapsed: self.collapsed,
+            spawned,
+        })
+    }
+
+    /// Get the spans in this tree waiting on some resource declared with
+    /// [`StackTrace::stack_trace_waiting_on`], as the path of spans from this node and the
+    /// resource. The spawned tasks are included, as they're reported as part of this task.
+    pub fn waiting_on(&self) -> Vec<(Vec<String>, String)> {
+        fn visit(
+            node: &SpanTreeNode,
+            path: &mut Vec<String>,
+            waiting: &mut Vec<(Vec<String>, String)>,
+        ) {
+            path.push(node.span.clone());
+            if let Some(resource) = node.fields.get(WAITS_ON) {
+                waiting.push((path.clone(), resource.to_string()));
+            }
+            for child in node.children.iter().chain(&node.spawned) {
+                visit(child, path, waiting);
+            }
+            path.pop();
+        }
+
+        let mut waiting = Vec::new();
+        visit(self, &mut Vec::new(), &mut waiting);
+        waiting
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Render the tree in the same way as the text report.
+impl std::fmt::Display for SpanTreeNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn fmt_node(
+            f: &mut std::fmt::Formatter<'_>,
+            node: &SpanTreeNode,
+            depth: usize,
+            mark: &str,
+        ) -> std::fmt::Result {
+            write!(f, "{}{}{}", " ".repeat(depth * 2), mark, node.span)?;
+            fmt_fields(f, &node.fields)?;
+            if let Some(items) = node.items {
+                write!(f, " (items: {})", items)?;
+            }
+            writeln!(
+                f,
+                " [{}{:?}]",
+                if node.slow { "!!! " } else { "" },
+                node.elapsed
+            )?;
+
+            for child in &node.children {
+                fmt_node(f, child, depth + 1, "")?;
+            }
+            if node.collapsed > 0 {
+                writeln!(
+                    f,
+                    "{}... {} more",
+                    " ".repeat((depth + 1) * 2),
+                    node.collapsed
+                )?;
+            }
+            for spawned in &node.spawned {
+                fmt_node(f, spawned, depth + 1, "[spawned] ")?;
+            }
+            Ok(())
         }
+
+        fmt_node(f, self, 0, "")
     }
 }
 
-type ContextId = u64;
+/// A task spawned with [`spawn_stack_traced`] under some span of this context.
+#[derive(Debug)]
+struct SpawnedTask {
+    /// The span where the task is spawned, or the nearest alive ancestor of it.
+    parent: NodeId,
+    /// Receives the reports from the spawned task.
+    rx: TraceReceiver,
+}
+
+impl SpawnedTask {
+    /// Whether the spawned task is still running, i.e., the reporter has not been dropped.
+    fn is_alive(&self) -> bool {
+        self.rx.has_changed().is_ok()
+    }
+}
 
+/// The [`TraceContext`] backed by an arena of span nodes.
 #[derive(Debug)]
-struct TraceContext {
-    id: ContextId,
+pub struct ArenaTraceContext {
     arena: Arena<SpanNode>,
     root: NodeId,
     current: NodeId,
+    /// The roots of the detached subtrees. The futures of them are still alive, and will be
+    /// re-attached to the current span once polled again.
+    detached: Vec<NodeId>,
+    spawned: Vec<SpawnedTask>,
+    /// The statistics of the completed spans, keyed by the span.
+    stats: HashMap<SpanValue, SpanStats>,
+    /// The resources held by this task, shared with the guards of [`hold_resource`] so that they
+    /// can be released wherever the guards are dropped.
+    held: Arc<Mutex<Vec<SpanValue>>>,
+    /// The count of alive nodes in the arena. The slots of the removed nodes are reused by the
+    /// arena.
+    active_nodes: usize,
+    /// The nodes collapsed for [`TraceConfig::max_nodes`], whose futures are still alive, mapped
+    /// to their parents and their state.
+    collapsed: HashMap<NodeId, (NodeId, SpanNode)>,
+    config: TraceConfig,
 }
 
-impl std::fmt::Display for TraceContext {
+impl std::fmt::Display for ArenaTraceContext {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         fn fmt_node(
             f: &mut std::fmt::Formatter<'_>,
-            arena: &Arena<SpanNode>,
+            context: &ArenaTraceContext,
             node: NodeId,
             depth: usize,
         ) -> std::fmt::Result {
+            let arena = &context.arena;
             f.write_str(&" ".repeat(depth * 2))?;
 
             let inner = arena[node].get();
             f.write_str(inner.span.as_ref())?;
+            fmt_fields(f, &inner.fields)?;
+            if let Some(items) = inner.items {
+                write!(f, " (items: {})", items)?;
+            }
 
             let elapsed = inner.start_time.elapsed();
             f.write_fmt(format_args!(
                 " [{}{:?}]",
-                if depth > 0 && elapsed.as_secs() >= 1 {
+                if depth > 0 && elapsed >= context.config.slow_threshold {
                     "!!! "
                 } else {
                     ""
@@ -122,80 +684,389 @@ impl std::fmt::Display for TraceContext {
                 .children(arena)
                 .sorted_by(|&a, &b| arena[a].get().span.cmp(&arena[b].get().span))
             {
-                fmt_node(f, arena, child, depth + 1)?;
+                fmt_node(f, context, child, depth + 1)?;
+            }
+            if inner.collapsed > 0 {
+                f.write_str(&" ".repeat((depth + 1) * 2))?;
+                writeln!(f, "... {} more", inner.collapsed)?;
+            }
+
+            for spawned in context.spawned_of(node) {
+                let report = spawned.rx.borrow();
+                for (i, line) in report.report.lines().enumerate() {
+                    f.write_str(&" ".repeat((depth + 1) * 2))?;
+                    if i == 0 {
+                        f.write_str("[spawned] ")?;
+                    }
+                    f.write_str(line)?;
+                    f.write_char('\n')?;
+                }
             }
 
             Ok(())
         }
 
-        fmt_node(f, &self.arena, self.root, 0)
+        fmt_node(f, self, self.root, 0)?;
+
+        for &node in &self.detached {
+            writeln!(f, "[Detached {}]", node)?;
+            fmt_node(f, self, node, 1)?;
+        }
+
+        Ok(())
     }
 }
 
-impl TraceContext {
-    /// Create a new stack trace context with the given root span.
-    fn new(root_span: SpanValue) -> Self {
-        static ID: AtomicU64 = AtomicU64::new(0);
-        let id = ID.fetch_add(1, Ordering::SeqCst);
-
+impl ArenaTraceContext {
+    /// Create a new stack trace context with the given root span and config.
+    fn with_config(root_span: SpanValue, config: TraceConfig) -> Self {
         let mut arena = Arena::new();
-        let root = arena.new_node(SpanNode::new(root_span));
+        let mut root = SpanNode::new(root_span);
+        if config.tracing_spans {
+            root.tracing_span = Some(new_tracing_span(&root.span, None));
+        }
+        let root = arena.new_node(root);
 
         Self {
-            id,
             arena,
             root,
             current: root,
+            detached: Vec::new(),
+            spawned: Vec::new(),
+            stats: HashMap::new(),
+            held: Default::default(),
+            active_nodes: 1,
+            collapsed: HashMap::new(),
+            config,
         }
     }
 
-    /// Get the count of active span nodes in this context.
-    #[cfg_attr(not(test), expect(dead_code))]
-    fn active_node_count(&self) -> usize {
-        self.arena.iter().filter(|n| !n.is_removed()).count()
-    }
-
     /// Get the report of the current state of the stack trace.
     fn to_report(&self) -> StackTraceReport {
         let report = format!("{}", self);
         StackTraceReport {
             report,
+            tree: Some(self.to_tree()),
+            detached: self
+                .detached
+                .iter()
+                .map(|&node| self.to_subtree(node, 0))
+                .collect(),
+            stats: self
+                .stats
+                .iter()
+                .map(|(span, stats)| (span.to_string(), stats.clone()))
+                .collect(),
+            held: self
+                .held
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|resource| resource.to_string())
+                .chain(
+                    self.spawned
+                        .iter()
+                        .filter(|spawned| spawned.is_alive())
+                        .flat_map(|spawned| spawned.rx.borrow().held.clone()),
+                )
+                .sorted()
+                .collect(),
+            arena: self.arena_stats(),
             capture_time: Instant::now(),
         }
     }
 
-    /// Push a new span as a child of current span. Returns the new current span.
-    fn push(&mut self, span: SpanValue) -> NodeId {
-        let child = self.arena.new_node(SpanNode::new(span));
+    /// Get the structured span tree of the current state, with children sorted the same way as
+    /// the [`Display`](std::fmt::Display) implementation.
+    fn to_tree(&self) -> SpanTreeNode {
+        self.to_subtree(self.root, 0)
+    }
+
+    /// Get the structured span tree rooted at `node`.
+    fn to_subtree(&self, node: NodeId, depth: usize) -> SpanTreeNode {
+        let arena = &self.arena;
+        let inner = arena[node].get();
+        let children = node
+            .children(arena)
+            .sorted_by(|&a, &b| arena[a].get().span.cmp(&arena[b].get().span))
+            .map(|child| self.to_subtree(child, depth + 1))
+            .collect();
+        let spawned = self
+            .spawned_of(node)
+            .filter_map(|spawned| spawned.rx.borrow().tree.clone())
+            .collect();
+
+        let elapsed = inner.start_time.elapsed();
+
+        SpanTreeNode {
+            id: node.into(),
+            parent_id: arena[node].parent().map(Into::into),
+            span: inner.span.to_string(),
+            depth,
+            elapsed,
+            slow: depth > 0 && elapsed >= self.config.slow_threshold,
+            items: inner.items,
+            fields: inner
+                .fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+            children,
+            collapsed: inner.collapsed,
+            spawned,
+        }
+    }
+
+    /// Find the spans crossing the slow threshold that have not been reported yet, and mark them
+    /// as reported.
+    fn take_slow_spans(&mut self) -> Vec<SlowSpan> {
+        let threshold = self.config.slow_threshold;
+        let slow_nodes = self
+            .root
+            .descendants(&self.arena)
+            .skip(1) // The root is always pending.
+            .filter(|&node| {
+                let inner = self.arena[node].get();
+                !inner.slow_reported && inner.start_time.elapsed() >= threshold
+            })
+            .collect_vec();
+
+        let path_of = |arena: &Arena<SpanNode>, node: NodeId| {
+            let mut path = node
+                .ancestors(arena)
+                .map(|n| arena[n].get().span.clone())
+                .collect_vec();
+            path.reverse();
+            path
+        };
+
+        let mut slow_spans = slow_nodes
+            .into_iter()
+            .map(|node| {
+                let path = path_of(&self.arena, node);
+                let inner = self.arena[node].get_mut();
+                inner.slow_reported = true;
+                SlowSpan {
+                    path,
+                    elapsed: inner.start_time.elapsed(),
+                }
+            })
+            .collect_vec();
+
+        // The collapsed spans are still pending, so watch them as well.
+        for (parent, inner) in self.collapsed.values_mut() {
+            if inner.slow_reported || inner.start_time.elapsed() < threshold {
+                continue;
+            }
+            let mut path = if parent.is_removed(&self.arena) {
+                Vec::new()
+            } else {
+                path_of(&self.arena, *parent)
+            };
+            path.push(inner.span.clone());
+            inner.slow_reported = true;
+            slow_spans.push(SlowSpan {
+                path,
+                elapsed: inner.start_time.elapsed(),
+            });
+        }
+        slow_spans
+    }
+
+    /// Get the alive tasks spawned under the given span.
+    fn spawned_of(&self, node: NodeId) -> impl Iterator<Item = &SpawnedTask> {
+        self.spawned
+            .iter()
+            .filter(move |spawned| spawned.parent == node && spawned.is_alive())
+    }
+
+    /// Register a task spawned under the current span, whose reports are sent to `rx`.
+    fn add_spawned(&mut self, rx: TraceReceiver) {
+        self.spawned.retain(SpawnedTask::is_alive);
+        self.spawned.push(SpawnedTask {
+            parent: self.current,
+            rx,
+        });
+    }
+
+    /// Move the spawned tasks under `from` to `to`, used when the span `from` is removed.
+    fn reparent_spawned(&mut self, from: NodeId, to: NodeId) {
+        for spawned in &mut self.spawned {
+            if spawned.parent == from {
+                spawned.parent = to;
+            }
+        }
+    }
+
+    /// Get the memory usage of the arena.
+    fn arena_stats(&self) -> ArenaStats {
+        ArenaStats {
+            active_nodes: self.active_nodes,
+            slots: self.arena.count(),
+            capacity: self.arena.capacity(),
+            bytes: self.arena.capacity() * std::mem::size_of::<indextree::Node<SpanNode>>(),
+            collapsed: self.collapsed.len(),
+        }
+    }
+
+    /// Collapse the oldest leaf spans into the placeholders of their parents, if there are no less
+    /// than [`TraceConfig::max_nodes`] nodes. The detached subtrees are included, whose roots are
+    /// collapsed under the root. To amortize the cost of finding them, an eighth of the limit
+    /// will be collapsed at a time.
+    fn collapse_if_full(&mut self) {
+        let Some(max_nodes) = self.config.max_nodes else {
+            return;
+        };
+        if self.active_nodes < max_nodes {
+            return;
+        }
+
+        let arena = &self.arena;
+        let leaves = std::iter::once(self.root)
+            .chain(self.detached.iter().copied())
+            .flat_map(|root| root.descendants(arena))
+            .filter(|&node| {
+                // The current span is going to have a child.
+                node != self.root && node != self.current && arena[node].first_child().is_none()
+            })
+            .sorted_by_key(|&node| arena[node].get().start_time)
+            .take((max_nodes / 8).max(self.active_nodes + 1 - max_nodes))
+            .collect_vec();
+
+        for node in leaves {
+            let parent = self.arena[node].parent().unwrap_or(self.root);
+            self.arena[parent].get_mut().collapsed += 1;
+            self.reparent_spawned(node, parent);
+            self.detached.retain(|&n| n != node);
+            let inner = std::mem::replace(
+                self.arena[node].get_mut(),
+                SpanNode::with_start_time(SpanValue::Borrowed(""), Instant::now()),
+            );
+            node.remove(&mut self.arena);
+            self.active_nodes -= 1;
+            self.collapsed.insert(node, (parent, inner));
+        }
+    }
+
+    /// Take the state of the collapsed `node` once it's pushed back or its future is dropped.
+    /// Returns `None` if it's not collapsed.
+    fn take_collapsed(&mut self, node: NodeId) -> Option<SpanNode> {
+        let (parent, inner) = self.collapsed.remove(&node)?;
+        if !parent.is_removed(&self.arena) {
+            self.arena[parent].get_mut().collapsed -= 1;
+        }
+        Some(inner)
+    }
+
+    /// Push the collapsed `node` back as a child of current span. Returns the new current span.
+    fn push_collapsed(&mut self, node: NodeId) -> Option<NodeId> {
+        let inner = self.take_collapsed(node)?;
+        let has_collapsed = inner.collapsed > 0;
+        let new_node = self.push_node(inner);
+        // Its collapsed children are now placeholders under the new node.
+        if has_collapsed {
+            for (parent, _) in self.collapsed.values_mut() {
+                if *parent == node {
+                    *parent = new_node;
+                }
+            }
+        }
+        Some(new_node)
+    }
+
+    /// Take the `node` out of this context, for its future migrated to another context. The
+    /// children are detached, and will be migrated as well once they're polled under the node.
+    fn take_node(&mut self, node: NodeId) -> SpanNode {
+        let mut inner = match self.take_collapsed(node) {
+            Some(inner) => inner,
+            None => {
+                let inner = std::mem::replace(
+                    self.arena[node].get_mut(),
+                    SpanNode::with_start_time(SpanValue::Borrowed(""), Instant::now()),
+                );
+                self.remove_and_detach(&node);
+                inner
+            }
+        };
+        // The collapsed children are left in this context.
+        inner.collapsed = 0;
+        inner
+    }
+
+    /// Push a new node as a child of current span. Returns the new current span.
+    fn push_node(&mut self, mut node: SpanNode) -> NodeId {
+        self.collapse_if_full();
+        if self.config.tracing_spans && node.tracing_span.is_none() {
+            let parent = self.arena[self.current].get().tracing_span.as_ref();
+            node.tracing_span = Some(new_tracing_span(&node.span, parent));
+        }
+        let child = self.arena.new_node(node);
+        self.active_nodes += 1;
         self.current.append(child, &mut self.arena);
         self.current = child;
         child
     }
 
-    /// Step in the current span to the given child.
-    ///
-    /// If the child is not actually a child of the current span, it means we are using a new future
-    /// to poll it, so we need to detach it from the previous parent, and attach it to the current
-    /// span.
-    fn step_in(&mut self, child: NodeId) {
+    /// Step out the current span to the parent, and detach it as a subtree. Used for the streams
+    /// that yield an item, which are idle until polled again and will be re-attached to the
+    /// current span then.
+    fn step_out_and_park(&mut self) {
+        let node = self.current;
+        self.step_out();
+        node.detach(&mut self.arena);
+        self.detached.push(node);
+    }
+}
+
+impl TraceContext for ArenaTraceContext {
+    type Node = NodeId;
+
+    fn new(root_span: SpanValue) -> Self {
+        Self::with_config(root_span, Default::default())
+    }
+
+    fn current(&self) -> NodeId {
+        self.current
+    }
+
+    fn push(&mut self, span: SpanValue) -> NodeId {
+        self.push_node(SpanNode::new(span))
+    }
+
+    /// This also re-attaches a detached subtree.
+    fn step_in(&mut self, &child: &NodeId) {
         if !self.current.children(&self.arena).contains(&child) {
             // Actually we can always call this even if `child` is already a child of `current`.
             self.current.append(child, &mut self.arena);
+            self.detached.retain(|&n| n != child);
         }
         self.current = child;
     }
 
-    /// Pop the current span to the parent.
+    /// The children still alive, which are polled by this future through a reference, will be
+    /// detached. The elapsed time is recorded to the statistics of the span.
     fn pop(&mut self) {
-        assert!(self.current.children(&self.arena).next().is_none());
-        let parent = self.arena[self.current]
+        let current = self.current;
+        let inner = self.arena[current].get();
+        let key =
+            if self.stats.len() < self.config.max_stats || self.stats.contains_key(&inner.span) {
+                inner.span.clone()
+            } else {
+                SpanValue::Borrowed(SpanStats::OTHERS)
+            };
+        self.stats
+            .entry(key)
+            .or_default()
+            .record(inner.start_time.elapsed());
+
+        let parent = self.arena[current]
             .parent()
             .expect("the root node should not be popped");
-        self.current.remove(&mut self.arena);
+        self.reparent_spawned(current, parent);
+        self.remove_and_detach(&current);
         self.current = parent;
     }
 
-    /// Step out the current span to the parent.
     fn step_out(&mut self) {
         let parent = self.arena[self.current]
             .parent()
@@ -203,40 +1074,187 @@ impl TraceContext {
         self.current = parent;
     }
 
-    /// Remove the current span and detach the children, used for future aborting. The children might be polled again later, and will be added as the children of a new span.
-    fn remove_and_detach(&mut self, node: NodeId) {
+    fn remove_and_detach(&mut self, &node: &NodeId) {
+        if node.is_removed(&self.arena) {
+            // Collapsed before, there's only the placeholder to update.
+            self.take_collapsed(node);
+            return;
+        }
+        // The spawned tasks are not cancelled with the future, so keep them under the root.
+        self.reparent_spawned(node, self.root);
+        self.detached.retain(|&n| n != node);
+
+        let children = node.children(&self.arena).collect_vec();
         node.detach(&mut self.arena);
         // Removing detached `node` makes children detached.
         node.remove(&mut self.arena);
+        self.active_nodes -= 1;
+        self.detached.extend(children);
+    }
+
+    fn active_node_count(&self) -> usize {
+        self.active_nodes
     }
 }
 
+/// The context of a task, shared with the futures polled in it, so that a future migrated to or
+/// dropped in another task can still move or remove its node.
+type SharedContext = Arc<Mutex<ArenaTraceContext>>;
+
 tokio::task_local! {
-    static TRACE_CONTEXT: RefCell<TraceContext>
+    static TRACE_CONTEXT: SharedContext
 }
 
 fn with_context<F, R>(f: F) -> R
 where
-    F: FnOnce(RefMut<TraceContext>) -> R,
+    F: FnOnce(MutexGuard<ArenaTraceContext>) -> R,
 {
     TRACE_CONTEXT.with(|trace_context| {
-        let trace_context = trace_context.borrow_mut();
+        let trace_context = trace_context.lock().unwrap();
         f(trace_context)
     })
 }
 
-/// State for stack traced future.
+/// Whether the stack trace is enabled at runtime.
+static STACK_TRACE_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Enable or disable the stack trace at runtime. The futures wrapped with
+/// [`StackTrace::stack_trace`] when disabled will never be traced, without touching the trace
+/// context at all.
+///
+/// This only skips the lookup of the task-local context and the allocation of the span nodes.
+/// The futures are still wrapped in [`StackTraced`], which checks the state on every poll before
+/// polling the inner one. To eliminate the cost completely, disable the `stack-trace` feature
+/// instead.
+pub fn set_stack_trace_enabled(enabled: bool) {
+    STACK_TRACE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// State for stack traced future or stream.
 enum StackTracedState {
-    Initial(SpanValue),
+    /// The stack trace is disabled at runtime when this future is created.
+    #[cfg(feature = "stack-trace")]
+    Disabled,
+    #[cfg(feature = "stack-trace")]
+    Initial(SpanValue, Fields),
+    /// Polled out of any context after being traced. The node is taken out of its context, and
+    /// will be pushed back once polled in a context again.
+    Taken(Box<SpanNode>),
     Polled {
         /// The node associated with this future.
         this_node: NodeId,
-        // The id of the context where this future is first polled.
-        this_context: ContextId,
+        /// The context where this future is last polled, which owns the node.
+        this_context: SharedContext,
     },
     Ready,
 }
 
+impl StackTracedState {
+    #[cfg(feature = "stack-trace")]
+    fn new(span: impl Into<SpanValue>, fields: Fields) -> Self {
+        if STACK_TRACE_ENABLED.load(Ordering::Relaxed) {
+            Self::Initial(span.into(), fields)
+        } else {
+            Self::Disabled
+        }
+    }
+
+    /// Step in the span of this future before polling the inner one, with the node pushed to the
+    /// current context if it's first polled or migrated from another context. Returns the node
+    /// and its `tracing` span to enter, or `None` if it should not be traced for this poll.
+    ///
+    /// Should not be called if [`StackTracedState::is_disabled`].
+    fn enter(&mut self) -> Option<(NodeId, Option<tracing::Span>)> {
+        if let Self::Ready = self {
+            return None;
+        }
+
+        // `None` if polled in the same context as before.
+        let current_context = TRACE_CONTEXT.try_with(|c| match self {
+            Self::Polled { this_context, .. } if Arc::ptr_eq(this_context, c) => None,
+            _ => Some(c.clone()),
+        });
+
+        let this_node = match current_context {
+            // Not in a context
+            Err(_) => {
+                if let Self::Polled {
+                    this_node,
+                    this_context,
+                } = self
+                {
+                    // It was traced when polled before. Take it out of the origin context and
+                    // won't be traced until polled in a context again.
+                    let node = this_context.lock().unwrap().take_node(*this_node);
+                    *self = Self::Taken(Box::new(node));
+                }
+                return None;
+            }
+            // Polled before in the same context, just step in, or push it back if collapsed.
+            Ok(None) => {
+                let Self::Polled { this_node, .. } = self else {
+                    unreachable!()
+                };
+                with_context(|mut c| match c.push_collapsed(*this_node) {
+                    Some(node) => *this_node = node,
+                    None => c.step_in(this_node),
+                });
+                *this_node
+            }
+            // First polled, or the context changed, push the node to the current context. On
+            // migration, the node is moved with its state, and the children will be migrated as
+            // well once they're polled under this node.
+            Ok(Some(current_context)) => {
+                let node = match std::mem::replace(self, Self::Ready) {
+                    #[cfg(feature = "stack-trace")]
+                    Self::Initial(span, fields) => SpanNode::new(span).with_fields(fields),
+                    Self::Taken(node) => *node,
+                    Self::Polled {
+                        this_node,
+                        this_context,
+                    } => this_context.lock().unwrap().take_node(this_node),
+                    _ => unreachable!(),
+                };
+                let this_node = current_context.lock().unwrap().push_node(node);
+                *self = Self::Polled {
+                    this_node,
+                    this_context: current_context,
+                };
+                this_node
+            }
+        };
+
+        let tracing_span = with_context(|c| {
+            // The current node must be the this_node.
+            assert_eq!(this_node, c.current);
+            c.arena[this_node].get().tracing_span.clone()
+        });
+
+        Some((this_node, tracing_span))
+    }
+
+    /// Whether the stack trace is disabled at runtime for this future, so that the inner one
+    /// should be polled directly without looking up the context.
+    fn is_disabled(&self) -> bool {
+        #[cfg(feature = "stack-trace")]
+        if let Self::Disabled = self {
+            return true;
+        }
+        false
+    }
+
+    /// Clean up the span of this future on drop, from the context where it's last polled.
+    fn clean_up(&mut self) {
+        if let Self::Polled {
+            this_node,
+            this_context,
+        } = self
+        {
+            this_context.lock().unwrap().remove_and_detach(this_node);
+        }
+    }
+}
+
 /// The future for [`StackTrace::stack_trace`].
 #[pin_project(PinnedDrop)]
 pub struct StackTraced<F: Future> {
@@ -248,10 +1266,11 @@ pub struct StackTraced<F: Future> {
 }
 
 impl<F: Future> StackTraced<F> {
-    fn new(inner: F, span: impl Into<SpanValue>) -> Self {
+    #[cfg(feature = "stack-trace")]
+    fn new(inner: F, span: impl Into<SpanValue>, fields: Fields) -> Self {
         Self {
             inner,
-            state: StackTracedState::Initial(span.into()),
+            state: StackTracedState::new(span, fields),
         }
     }
 }
@@ -259,64 +1278,27 @@ impl<F: Future> StackTraced<F> {
 impl<F: Future> Future for StackTraced<F> {
     type Output = F::Output;
 
-    // TODO: may disable based on features
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
-        let current_context = TRACE_CONTEXT.try_with(|c| c.borrow().id);
+        if let StackTracedState::Ready = this.state {
+            unreachable!("the traced future should always be fused");
+        }
+        if this.state.is_disabled() {
+            return this.inner.poll(cx);
+        }
 
         // For assertion.
-        let old_current = with_context(|c| c.current.clone());
-
-        let this_node = match this.state {
-            StackTracedState::Initial(span) => {
-                match current_context {
-                    // First polled
-                    Ok(current_context) => {
-                        // First polled, push a new span to the context.
-                        let node = with_context(|mut c| c.push(std::mem::take(span)));
-                        *this.state = StackTracedState::Polled {
-                            this_node: node,
-                            this_context: current_context,
-                        };
-                        node
-                    }
-                    // Not in a context
-                    Err(_) => return this.inner.poll(cx),
-                }
-            }
-            StackTracedState::Polled {
-                this_node,
-                this_context,
-            } => {
-                match current_context {
-                    // Context correct
-                    Ok(current_context) if current_context == *this_context => {
-                        // Polled before, just step in.
-                        with_context(|mut c| c.step_in(*this_node));
-                        *this_node
-                    }
-                    // Context changed
-                    Ok(_) => {
-                        tracing::warn!("stack traced future is polled in a different context as it was first polled, won't be traced now");
-                        return this.inner.poll(cx);
-                    }
-                    // Out of context
-                    Err(_) => {
-                        tracing::warn!("stack traced future is not polled in a traced context, while it was when first polled, won't be traced now");
-                        return this.inner.poll(cx);
-                    }
-                }
-            }
-            StackTracedState::Ready => unreachable!("the traced future should always be fused"),
-        };
+        let old_current = TRACE_CONTEXT.try_with(|c| c.lock().unwrap().current);
 
-        // The current node must be the this_node.
-        assert_eq!(this_node, with_context(|c| c.current.clone()));
+        let Some((this_node, tracing_span)) = this.state.enter() else {
+            return this.inner.poll(cx);
+        };
+        let _entered = tracing_span.as_ref().map(tracing::Span::enter);
 
         let r = match this.inner.poll(cx) {
             // The future is ready, clean-up this span by popping from the context.
             Poll::Ready(output) => {
-                assert_eq!(this_node, with_context(|c| c.current.clone()));
+                assert_eq!(this_node, with_context(|c| c.current));
                 with_context(|mut c| c.pop());
                 *this.state = StackTracedState::Ready;
                 Poll::Ready(output)
@@ -329,7 +1311,7 @@ impl<F: Future> Future for StackTraced<F> {
         };
 
         // The current node must be the same as we started with.
-        assert_eq!(old_current, with_context(|c| c.current.clone()));
+        assert_eq!(old_current.ok(), Some(with_context(|c| c.current)));
 
         r
     }
@@ -338,29 +1320,7 @@ impl<F: Future> Future for StackTraced<F> {
 #[pinned_drop]
 impl<F: Future> PinnedDrop for StackTraced<F> {
     fn drop(self: Pin<&mut Self>) {
-        let this = self.project();
-        let current_context = TRACE_CONTEXT.try_with(|c| c.borrow().id);
-
-        match this.state {
-            StackTracedState::Polled {
-                this_node,
-                this_context,
-            } => match current_context {
-                // Context correct
-                Ok(current_context) if current_context == *this_context => {
-                    with_context(|mut c| c.remove_and_detach(*this_node));
-                }
-                // Context changed
-                Ok(_) => {
-                    tracing::warn!("stack traced future is dropped in a different context as it was first polled, cannot clean up!");
-                }
-                // Out of context
-                Err(_) => {
-                    tracing::warn!("stack traced future is not in a traced context, while it was when first polled, cannot clean up!");
-                }
-            },
-            StackTracedState::Initial(_) | StackTracedState::Ready => {}
-        }
+        self.project().state.clean_up();
     }
 }
 
@@ -369,13 +1329,304 @@ impl<T> StackTrace for T where T: Future {}
 pub trait StackTrace: Future + Sized {
     /// Wrap this future, so that we're able to check the stack trace and find where and why this
     /// future is pending, with [`StackTraceReport`] and [`StackTraceManager`].
+    #[cfg(feature = "stack-trace")]
     fn stack_trace(self, span: impl Into<SpanValue>) -> Fuse<StackTraced<Self>> {
-        StackTraced::new(self, span).fuse()
+        StackTraced::new(self, span, Fields::new()).fuse()
+    }
+
+    /// The stack trace is disabled by the `stack-trace` feature, return this future as-is.
+    #[cfg(not(feature = "stack-trace"))]
+    fn stack_trace(self, _span: impl Into<SpanValue>) -> Self {
+        self
+    }
+
+    /// Like [`StackTrace::stack_trace`], but with the given fields on the span, instead of baking
+    /// them into the span with `format!`. The fields can be updated with [`record_field`] while
+    /// this future is pending.
+    #[cfg(feature = "stack-trace")]
+    fn stack_trace_with_fields(
+        self,
+        span: impl Into<SpanValue>,
+        fields: impl IntoIterator<Item = (&'static str, FieldValue)>,
+    ) -> Fuse<StackTraced<Self>> {
+        StackTraced::new(self, span, fields.into_iter().collect()).fuse()
+    }
+
+    /// The stack trace is disabled by the `stack-trace` feature, return this future as-is.
+    #[cfg(not(feature = "stack-trace"))]
+    fn stack_trace_with_fields(
+        self,
+        _span: impl Into<SpanValue>,
+        _fields: impl IntoIterator<Item = (&'static str, FieldValue)>,
+    ) -> Self {
+        self
+    }
+
+    /// Like [`StackTrace::stack_trace`], but declares that this future waits on the given
+    /// resource, e.g., a lock id or a channel id, with the field [`WAITS_ON`]. The task holding
+    /// the resource should declare it with [`hold_resource`], so that the deadlocks can be found
+    /// with [`StackTraceManager::find_deadlocks`].
+    #[cfg(feature = "stack-trace")]
+    fn stack_trace_waiting_on(
+        self,
+        span: impl Into<SpanValue>,
+        resource: impl Into<SpanValue>,
+    ) -> Fuse<StackTraced<Self>> {
+        self.stack_trace_with_fields(span, [(WAITS_ON, FieldValue::Str(resource.into()))])
+    }
+
+    /// The stack trace is disabled by the `stack-trace` feature, return this future as-is.
+    #[cfg(not(feature = "stack-trace"))]
+    fn stack_trace_waiting_on(
+        self,
+        _span: impl Into<SpanValue>,
+        _resource: impl Into<SpanValue>,
+    ) -> Self {
+        self
     }
 }
 
-pub type TraceSender = watch::Sender<StackTraceReport>;
-pub type TraceReceiver = watch::Receiver<StackTraceReport>;
+/// Get the spans from the root to the current span, i.e., the innermost span of
+/// [`StackTrace::stack_trace`] that is being polled. Returns `None` if not in a traced context.
+///
+/// Registered to [`arena_path`] once a task is traced, for the errors to capture.
+pub fn current_span_path() -> Option<Vec<String>> {
+    TRACE_CONTEXT
+        .try_with(|c| {
+            let c = c.lock().unwrap();
+            let mut path = c
+                .current
+                .ancestors(&c.arena)
+                .map(|node| c.arena[node].get().span.to_string())
+                .collect_vec();
+            path.reverse();
+            path
+        })
+        .ok()
+}
+
+/// The field declaring the resource that a span waits on, set by
+/// [`StackTrace::stack_trace_waiting_on`].
+pub const WAITS_ON: &str = "waits_on";
+
+/// Declare that the current task holds the given resource, e.g., a lock id or a channel id, until
+/// the returned guard is dropped. Does nothing if not in a traced context.
+pub fn hold_resource(resource: impl Into<SpanValue>) -> HeldResource {
+    let resource = resource.into();
+    let held = TRACE_CONTEXT
+        .try_with(|c| {
+            let held = c.lock().unwrap().held.clone();
+            held.lock().unwrap().push(resource.clone());
+            held
+        })
+        .ok();
+
+    HeldResource { held, resource }
+}
+
+/// The guard returned by [`hold_resource`], releasing the resource once dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct HeldResource {
+    held: Option<Arc<Mutex<Vec<SpanValue>>>>,
+    resource: SpanValue,
+}
+
+impl Drop for HeldResource {
+    fn drop(&mut self) {
+        if let Some(held) = &self.held {
+            let mut held = held.lock().unwrap();
+            if let Some(i) = held.iter().position(|r| r == &self.resource) {
+                held.swap_remove(i);
+            }
+        }
+    }
+}
+
+/// Record the field on the current span, i.e., the innermost span of [`StackTrace::stack_trace`]
+/// that is being polled, or the root span if there's none. Does nothing if not in a traced
+/// context.
+pub fn record_field(key: &'static str, value: impl Into<FieldValue>) {
+    let _ = TRACE_CONTEXT.try_with(|c| {
+        let mut c = c.lock().unwrap();
+        let current = c.current;
+        c.arena[current].get_mut().fields.insert(key, value.into());
+    });
+}
+
+/// The stream for [`StackTraceStream::stack_trace_stream`].
+#[pin_project(PinnedDrop)]
+pub struct StackTracedStream<S: Stream> {
+    #[pin]
+    inner: S,
+
+    /// The state of the span of this stream, which persists across the items.
+    state: StackTracedState,
+
+    /// The count of items yielded.
+    items: usize,
+
+    /// Whether an item is just yielded, so that the span should be restarted on the next poll.
+    yielded: bool,
+}
+
+impl<S: Stream> StackTracedStream<S> {
+    #[cfg(feature = "stack-trace")]
+    fn new(inner: S, span: impl Into<SpanValue>) -> Self {
+        Self {
+            inner,
+            state: StackTracedState::new(span, Fields::new()),
+            items: 0,
+            yielded: false,
+        }
+    }
+}
+
+impl<S: Stream> Stream for StackTracedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if this.state.is_disabled() {
+            return this.inner.poll_next(cx);
+        }
+
+        // Restart the timing for the next item.
+        let restart_time = std::mem::take(this.yielded).then(Instant::now);
+
+        // For assertion.
+        let old_current = TRACE_CONTEXT.try_with(|c| c.lock().unwrap().current);
+
+        let Some((this_node, tracing_span)) = this.state.enter() else {
+            return this.inner.poll_next(cx);
+        };
+        let _entered = tracing_span.as_ref().map(tracing::Span::enter);
+
+        with_context(|mut c| {
+            let inner = c.arena[this_node].get_mut();
+            inner.items = Some(*this.items);
+            if let Some(restart_time) = restart_time {
+                inner.start_time = restart_time;
+                inner.slow_reported = false;
+            }
+        });
+
+        let r = match this.inner.poll_next(cx) {
+            // An item is yielded, park this span until the next poll.
+            Poll::Ready(Some(item)) => {
+                assert_eq!(this_node, with_context(|c| c.current));
+                *this.items += 1;
+                *this.yielded = true;
+                with_context(|mut c| {
+                    c.arena[this_node].get_mut().items = Some(*this.items);
+                    c.step_out_and_park();
+                });
+                Poll::Ready(Some(item))
+            }
+            // The stream is terminated, clean-up this span by popping from the context.
+            Poll::Ready(None) => {
+                assert_eq!(this_node, with_context(|c| c.current));
+                with_context(|mut c| c.pop());
+                *this.state = StackTracedState::Ready;
+                Poll::Ready(None)
+            }
+            // Still pending, just step out.
+            Poll::Pending => {
+                with_context(|mut c| c.step_out());
+                Poll::Pending
+            }
+        };
+
+        // The current node must be the same as we started with.
+        assert_eq!(old_current.ok(), Some(with_context(|c| c.current)));
+
+        r
+    }
+}
+
+#[pinned_drop]
+impl<S: Stream> PinnedDrop for StackTracedStream<S> {
+    fn drop(self: Pin<&mut Self>) {
+        self.project().state.clean_up();
+    }
+}
+
+impl<T> StackTraceStream for T where T: Stream {}
+
+pub trait StackTraceStream: Stream + Sized {
+    /// Wrap this stream with a single span, which persists across the items instead of a new span
+    /// for each `next`. The span is shown with the count of items yielded and the time the current
+    /// `poll_next` has been pending, and is shown as a detached subtree while the stream is idle.
+    ///
+    /// Named differently from [`StackTrace::stack_trace`], so that it's not ambiguous for the types
+    /// implementing both `Future` and `Stream`.
+    #[cfg(feature = "stack-trace")]
+    fn stack_trace_stream(self, span: impl Into<SpanValue>) -> StackTracedStream<Self> {
+        StackTracedStream::new(self, span)
+    }
+
+    /// The stack trace is disabled by the `stack-trace` feature, return this stream as-is.
+    #[cfg(not(feature = "stack-trace"))]
+    fn stack_trace_stream(self, _span: impl Into<SpanValue>) -> Self {
+        self
+    }
+}
+
+/// The maximum time to wait for the spawned tasks to capture their reports, when capturing the
+/// report of the parent task.
+const SPAWNED_REPORT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Sends the captured reports of a traced task. Created with [`trace_channel`].
+#[derive(Debug)]
+pub struct TraceSender {
+    tx: watch::Sender<StackTraceReport>,
+    request: Arc<Notify>,
+}
+
+/// Receives the captured reports of a traced task. Created with [`trace_channel`].
+#[derive(Debug, Clone)]
+pub struct TraceReceiver {
+    rx: watch::Receiver<StackTraceReport>,
+    request: Arc<Notify>,
+}
+
+impl TraceReceiver {
+    /// Request the traced task to capture a new report. The task will be woken up and capture it
+    /// on its next poll, which can be waited with [`watch::Receiver::changed`].
+    pub fn request(&self) {
+        self.request.notify_one();
+    }
+}
+
+impl Deref for TraceReceiver {
+    type Target = watch::Receiver<StackTraceReport>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+impl DerefMut for TraceReceiver {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rx
+    }
+}
+
+/// Create a channel for the reports of a traced task.
+pub fn trace_channel() -> (TraceSender, TraceReceiver) {
+    let (tx, rx) = watch::channel(Default::default());
+    let request = Arc::new(Notify::new());
+    (
+        TraceSender {
+            tx,
+            request: request.clone(),
+        },
+        TraceReceiver { rx, request },
+    )
+}
 
 /// Manages the stack traces of multiple tasks.
 #[derive(Default, Debug)]
@@ -389,12 +1640,12 @@ where
 {
     /// Register with given key. Returns a sender that should be provided to [`stack_traced`].
     pub fn register(&mut self, key: K) -> TraceSender {
-        let (tx, rx) = watch::channel(Default::default());
+        let (tx, rx) = trace_channel();
         self.rxs.try_insert(key, rx).unwrap();
         tx
     }
 
-    /// Get all trace reports registered in this manager.
+    /// Get all trace reports registered in this manager, which are captured on the last request.
     ///
     /// Note that the reports might not be updated if the traced task is doing some computation
     /// heavy work and never yields, one may see the captured time to check this.
@@ -402,27 +1653,233 @@ where
         self.rxs.retain(|_, rx| rx.has_changed().is_ok());
         self.rxs.iter_mut().map(|(k, v)| (k, v.borrow_and_update()))
     }
+
+    /// Request all registered tasks to capture new reports.
+    pub fn request_all(&self) {
+        self.rxs.values().for_each(TraceReceiver::request);
+    }
+
+    /// Request all registered tasks to capture new reports, and wait for them for at most
+    /// `timeout` before getting all the reports. If some task does not respond in time, its
+    /// previous report will be returned.
+    pub async fn capture_all(
+        &mut self,
+        timeout: Duration,
+    ) -> impl Iterator<Item = (&K, watch::Ref<StackTraceReport>)> {
+        let changed = self.rxs.values_mut().map(|rx| {
+            // Mark the previous report as seen, so that we'll wait for the new one.
+            rx.borrow_and_update();
+            rx.request();
+            rx.changed()
+        });
+        let _ = tokio::time::timeout(timeout, futures::future::join_all(changed)).await;
+
+        self.get_all()
+    }
+
+    /// Find the deadlocks among the registered tasks from the reports captured on the last
+    /// request.
+    ///
+    /// A wait-for graph is built from the spans declared with
+    /// [`StackTrace::stack_trace_waiting_on`], where a task waits for the tasks holding the
+    /// resource with [`hold_resource`]. Each cycle found in the graph is returned as a
+    /// [`Deadlock`].
+    ///
+    /// The tasks spawned with [`spawn_stack_traced`] are considered part of the registered task
+    /// spawning them, since they're reported as part of it. So a spawned task waiting on a resource
+    /// held by its spawner is reported as the spawner waiting on itself.
+    pub fn find_deadlocks(&mut self) -> Vec<Deadlock<K>>
+    where
+        K: Clone,
+    {
+        let reports = self
+            .get_all()
+            .map(|(k, report)| (k.clone(), report.clone()))
+            .collect_vec();
+
+        let mut holders: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, (_, report)) in reports.iter().enumerate() {
+            for resource in report.held.iter().dedup() {
+                holders.entry(resource).or_default().push(i);
+            }
+        }
+
+        // The edges from each task to the tasks it waits for.
+        let mut edges = vec![Vec::new(); reports.len()];
+        for ((key, report), edges) in reports.iter().zip_eq(&mut edges) {
+            let waiting = report.tree.iter().chain(&report.detached);
+            for (path, resource) in waiting.flat_map(SpanTreeNode::waiting_on) {
+                for &holder in holders.get(resource.as_str()).into_iter().flatten() {
+                    let wait_for = WaitFor {
+                        task: key.clone(),
+                        path: path.clone(),
+                        resource: resource.clone(),
+                        holder: reports[holder].0.clone(),
+                    };
+                    edges.push((holder, wait_for));
+                }
+            }
+        }
+
+        /// Find the cycles with depth-first search, where each back edge forms a cycle.
+        fn visit<K: Clone>(
+            task: usize,
+            edges: &[Vec<(usize, WaitFor<K>)>],
+            visited: &mut [Option<bool>],
+            stack: &mut Vec<(usize, WaitFor<K>)>,
+            deadlocks: &mut Vec<Deadlock<K>>,
+        ) {
+            // `Some(false)` for the tasks on the stack, and `Some(true)` for the finished ones.
+            visited[task] = Some(false);
+            for (holder, wait_for) in &edges[task] {
+                stack.push((task, wait_for.clone()));
+                match visited[*holder] {
+                    None => visit(*holder, edges, visited, stack, deadlocks),
+                    Some(false) => {
+                        let start = stack.iter().position(|(t, _)| t == holder).unwrap();
+                        let cycle = stack[start..].iter().map(|(_, w)| w.clone()).collect();
+                        deadlocks.push(Deadlock { cycle });
+                    }
+                    Some(true) => {}
+                }
+                stack.pop();
+            }
+            visited[task] = Some(true);
+        }
+
+        let mut visited = vec![None; reports.len()];
+        let mut deadlocks = Vec::new();
+        for task in 0..reports.len() {
+            if visited[task].is_none() {
+                visit(task, &edges, &mut visited, &mut Vec::new(), &mut deadlocks);
+            }
+        }
+        deadlocks
+    }
+}
+
+/// A task waiting for a resource held by another task, or itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitFor<K> {
+    pub task: K,
+    /// The spans from the root of the task to the span waiting on the resource.
+    pub path: Vec<String>,
+    pub resource: String,
+    pub holder: K,
+}
+
+/// A cycle in the wait-for graph found by [`StackTraceManager::find_deadlocks`]. Each task in the
+/// cycle waits for the next one, and the last one waits for the first one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deadlock<K> {
+    pub cycle: Vec<WaitFor<K>>,
+}
+
+impl<K: Debug> std::fmt::Display for Deadlock<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "deadlock among {} tasks:", self.cycle.len())?;
+        for wait_for in &self.cycle {
+            writeln!(
+                f,
+                "  {:?} waits on `{}` held by {:?}, at: {}",
+                wait_for.task,
+                wait_for.resource,
+                wait_for.holder,
+                wait_for.path.join(" -> ")
+            )?;
+        }
+        Ok(())
+    }
 }
 
 /// Provide a stack tracing context with the `root_span` for the given future `f`. A reporter will
-/// be started in the current task and update the captured stack trace report through the given
-/// `trace_sender` every `interval` time.
+/// be started in the current task and capture the stack trace report through the given
+/// `trace_sender` once requested by [`TraceReceiver::request`].
 pub async fn stack_traced<F: Future>(
     f: F,
     root_span: impl Into<SpanValue>,
     trace_sender: TraceSender,
-    interval: Duration,
 ) -> F::Output {
+    stack_traced_with_config(f, root_span, trace_sender, Default::default()).await
+}
+
+/// Like [`stack_traced`], but with the given `config`. Besides the reporter, a watchdog will be
+/// started to check the spans crossing [`TraceConfig::slow_threshold`] every half of the
+/// threshold.
+pub async fn stack_traced_with_config<F: Future>(
+    f: F,
+    root_span: impl Into<SpanValue>,
+    trace_sender: TraceSender,
+    config: TraceConfig,
+) -> F::Output {
+    let report_interval = config.report_interval;
+    let slow_threshold = config.slow_threshold;
+    let on_slow_span = config.on_slow_span.clone();
+    let watchdog_enabled = on_slow_span.is_some() || config.warn_slow_spans;
+    arena_path::register(current_span_path);
+
     TRACE_CONTEXT
         .scope(
-            RefCell::new(TraceContext::new(root_span.into())),
+            Arc::new(Mutex::new(ArenaTraceContext::with_config(
+                root_span.into(),
+                config,
+            ))),
             async move {
-                let reporter = async move {
-                    let mut interval = tokio::time::interval(interval);
+                let watchdog = async move {
+                    if !watchdog_enabled {
+                        return futures::future::pending().await;
+                    }
+                    let mut interval =
+                        tokio::time::interval((slow_threshold / 2).max(Duration::from_millis(1)));
                     loop {
                         interval.tick().await;
-                        let new_trace = TRACE_CONTEXT.with(|c| c.borrow().to_report());
-                        match trace_sender.send(new_trace) {
+                        let slow_spans = with_context(|mut c| c.take_slow_spans());
+                        for slow_span in &slow_spans {
+                            match &on_slow_span {
+                                Some(callback) => callback(slow_span),
+                                None => tracing::warn!("slow span detected: {}", slow_span),
+                            }
+                        }
+                    }
+                };
+
+                let reporter = async move {
+                    let mut interval = report_interval.map(tokio::time::interval);
+                    loop {
+                        let periodic = async {
+                            match interval.as_mut() {
+                                Some(interval) => {
+                                    interval.tick().await;
+                                }
+                                None => futures::future::pending().await,
+                            }
+                        };
+                        tokio::select! {
+                            _ = trace_sender.request.notified() => {}
+                            _ = periodic => {}
+                        }
+
+                        // Request the spawned tasks as well, and wait for their new reports.
+                        let spawned_changed = with_context(|c| {
+                            c.spawned
+                                .iter()
+                                .filter(|spawned| spawned.is_alive())
+                                .map(|spawned| {
+                                    let mut rx = spawned.rx.clone();
+                                    rx.borrow_and_update();
+                                    rx.request();
+                                    async move { rx.changed().await }
+                                })
+                                .collect_vec()
+                        });
+                        let _ = tokio::time::timeout(
+                            SPAWNED_REPORT_TIMEOUT,
+                            futures::future::join_all(spawned_changed),
+                        )
+                        .await;
+
+                        let new_trace = with_context(|c| c.to_report());
+                        match trace_sender.tx.send(new_trace) {
                             Ok(_) => {}
                             Err(e) => {
                                 tracing::error!("Trace report error: failed to send trace: {}", e);
@@ -434,135 +1891,1089 @@ pub async fn stack_traced<F: Future>(
 
                 tokio::select! {
                     output = f => output,
-                    _ = reporter => unreachable!()
+                    _ = reporter => unreachable!(),
+                    _ = watchdog => unreachable!(),
                 }
             },
         )
         .await
 }
 
-#[cfg(test)]
-mod tests {
-    use futures::future::{join_all, select_all};
-    use futures::StreamExt;
-    use futures_async_stream::stream;
-    use tokio::sync::watch;
+/// Spawn a new task for the given future `f`, with a new stack tracing context of `root_span`.
+///
+/// If called in a traced context, the spawned task inherits the [`TraceConfig`] of the caller,
+/// and its reports are rendered under the current span of the caller with a `[spawned]` mark, so
+/// that the report of the caller shows the whole fan-out. Otherwise, the future is spawned without
+/// tracing.
+pub fn spawn_stack_traced<F>(
+    f: F,
+    root_span: impl Into<SpanValue>,
+) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let root_span = root_span.into();
+    let (trace_sender, rx) = trace_channel();
+
+    let config = TRACE_CONTEXT.try_with(|c| {
+        let mut c = c.lock().unwrap();
+        c.add_spawned(rx);
+        c.config.clone()
+    });
+
+    match config {
+        Ok(config) => tokio::spawn(stack_traced_with_config(f, root_span, trace_sender, config)),
+        Err(_) => tokio::spawn(f),
+    }
+}
+
+/// An embeddable HTTP service for the reports of a [`StackTraceManager`], so that the stack traces
+/// of all tasks can be checked with `curl` without printing them in every binary.
+///
+/// - `GET /`: the reports of all tasks as plain-text trees.
+/// - `GET /json`: the reports of all tasks as JSON, keyed by the task.
+///
+/// Both accept the following query parameters:
+/// - `key`: only the report of the given task, or `404` if not found.
+/// - `min_elapsed_ms`: only the spans pending for at least this long.
+/// - `max_depth`: only the spans at most this deep below the root.
+/// - `field`: in the form of `key=value`, only the spans with the given field and their ancestors.
+pub mod http {
+    use std::convert::Infallible;
+    use std::fmt::Display;
+    use std::net::SocketAddr;
+
+    use hyper::header::CONTENT_TYPE;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
 
     use super::*;
 
-    async fn sleep(time: u64) {
-        tokio::time::sleep(std::time::Duration::from_millis(time)).await;
-        println!("slept {time}ms");
+    /// The maximum time to wait for the tasks to capture their reports on each request.
+    const CAPTURE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub type SharedStackTraceManager<K> = Arc<tokio::sync::Mutex<StackTraceManager<K>>>;
+
+    #[derive(Debug, Default, Deserialize)]
+    struct Query {
+        key: Option<String>,
+        min_elapsed_ms: Option<u64>,
+        max_depth: Option<usize>,
+        field: Option<String>,
     }
 
-    async fn sleep_nested() {
-        join_all([
-            sleep(1500).stack_trace("sleep nested 1500"),
-            sleep(2500).stack_trace("sleep nested 2500"),
-        ])
-        .await;
+    fn error_response(status: StatusCode, message: impl Display) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .body(Body::from(message.to_string()))
+            .unwrap()
     }
 
-    async fn multi_sleep() {
-        sleep(400).await;
+    /// Handle a request for the reports of the `manager`, which can be embedded into an existing
+    /// hyper service.
+    pub async fn handle<K>(
+        manager: SharedStackTraceManager<K>,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, Infallible>
+    where
+        K: Hash + Eq + std::fmt::Debug + Display,
+    {
+        let json = match req.uri().path() {
+            "/" => false,
+            "/json" => true,
+            path => return Ok(error_response(StatusCode::NOT_FOUND, path)),
+        };
+        let query: Query = match serde_urlencoded::from_str(req.uri().query().unwrap_or_default()) {
+            Ok(query) => query,
+            Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, e)),
+        };
 
-        sleep(800).stack_trace("sleep another in multi slepp").await;
+        // Only hold the lock for cloning the receivers, instead of across the capture.
+        let mut rxs = {
+            let manager = manager.lock().await;
+            manager
+                .rxs
+                .iter()
+                .filter(|(_, rx)| rx.has_changed().is_ok())
+                .map(|(key, rx)| (key.to_string(), rx.clone()))
+                .filter(|(key, _)| query.key.as_ref().is_none_or(|k| k == key))
+                .collect_vec()
+        };
+        if let Some(key) = &query.key {
+            if rxs.is_empty() {
+                return Ok(error_response(StatusCode::NOT_FOUND, key));
+            }
+        }
+
+        let changed = rxs.iter_mut().map(|(_, rx)| {
+            // Mark the previous report as seen, so that we'll wait for the new one.
+            rx.borrow_and_update();
+            rx.request();
+            rx.changed()
+        });
+        let _ = tokio::time::timeout(CAPTURE_TIMEOUT, futures::future::join_all(changed)).await;
+
+        let mut reports = rxs
+            .into_iter()
+            .map(|(key, rx)| (key, rx.borrow().clone()))
+            .collect_vec();
+        reports.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        if let Some(field) = &query.field {
+            let Some((key, value)) = field.split_once('=') else {
+                return Ok(error_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid field `{}`, expect `key=value`", field),
+                ));
+            };
+            for (_, report) in &mut reports {
+                *report = report.filtered_by_field(key, value);
+            }
+        }
+
+        if query.min_elapsed_ms.is_some() || query.max_depth.is_some() {
+            let min_elapsed = Duration::from_millis(query.min_elapsed_ms.unwrap_or_default());
+            for (_, report) in &mut reports {
+                *report = report.filtered(min_elapsed, query.max_depth);
+            }
+        }
+
+        let (content_type, body) = if json {
+            let reports: serde_json::Map<_, _> = reports
+                .into_iter()
+                .map(|(key, report)| (key, report.to_json()))
+                .collect();
+            (
+                "application/json",
+                serde_json::Value::from(reports).to_string(),
+            )
+        } else {
+            let mut body = String::new();
+            for (key, report) in reports {
+                writeln!(body, "[{}]\n{}", key, report).unwrap();
+            }
+            ("text/plain; charset=utf-8", body)
+        };
+
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap())
     }
 
-    #[stream(item = ())]
-    async fn stream1() {
-        loop {
-            sleep(150).await;
-            yield;
+    /// Serve the reports of the `manager` on the given address.
+    pub async fn serve<K>(
+        manager: SharedStackTraceManager<K>,
+        addr: SocketAddr,
+    ) -> hyper::Result<()>
+    where
+        K: Hash + Eq + std::fmt::Debug + Display + Send + 'static,
+    {
+        let make_service = make_service_fn(move |_conn| {
+            let manager = manager.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(manager.clone(), req))) }
+        });
+
+        Server::bind(&addr).serve(make_service).await
+    }
+}
+
+#[cfg(all(test, feature = "stack-trace"))]
+mod tests {
+    use rusty_fork::rusty_fork_test;
+
+    use super::*;
+
+    async fn run<F: Future>(f: F) -> F::Output {
+        let (trace_sender, _trace_receiver) = trace_channel();
+        stack_traced(f, "root", trace_sender).await
+    }
+
+    stack_trace_scenarios!();
+
+    #[test]
+    fn test_span_tree_export() {
+        let mut context = ArenaTraceContext::new("actor".into());
+        let foo = context.push("foo".into());
+        context.push("foo inner".into());
+        context.step_out();
+        context.step_out();
+        let bar = context.push("bar".into());
+        context.step_out();
+
+        let tree = context.to_tree();
+        let simplified = |node: &SpanTreeNode| {
+            (
+                node.span.clone(),
+                node.depth,
+                node.parent_id,
+                node.children.len(),
+            )
+        };
+        assert_eq!(simplified(&tree), ("actor".to_owned(), 0, None, 2));
+        // Children are sorted by span.
+        let [bar_node, foo_node] = &tree.children[..] else {
+            unreachable!()
+        };
+        assert_eq!(bar_node.id, Into::<usize>::into(bar));
+        assert_eq!(
+            simplified(bar_node),
+            ("bar".to_owned(), 1, Some(tree.id), 0)
+        );
+        assert_eq!(foo_node.id, Into::<usize>::into(foo));
+        assert_eq!(
+            simplified(foo_node),
+            ("foo".to_owned(), 1, Some(tree.id), 1)
+        );
+        assert_eq!(
+            simplified(&foo_node.children[0]),
+            ("foo inner".to_owned(), 2, Some(foo_node.id), 0)
+        );
+
+        let json = context.to_report().to_json();
+        assert!(json["captured_ago_us"].is_u64());
+        assert_eq!(
+            json["tree"]["children"][1]["children"][0]["span"],
+            "foo inner"
+        );
+        let deserialized: SpanTreeNode = serde_json::from_value(json["tree"].clone()).unwrap();
+        assert_eq!(deserialized.span, tree.span);
+        assert_eq!(deserialized.children.len(), tree.children.len());
+    }
+
+    #[tokio::test]
+    async fn test_spawn_stack_traced() {
+        let mut manager = StackTraceManager::default();
+        let trace_sender = manager.register("actor");
+
+        let actor = async {
+            let child = async {
+                let grandchild =
+                    spawn_stack_traced(sleep(400).stack_trace("grandchild sleep"), "grandchild");
+                grandchild.stack_trace("join grandchild").await.unwrap();
+            };
+            spawn_stack_traced(child, "child")
+                .stack_trace("join child")
+                .await
+                .unwrap();
+        };
+        let actor = tokio::spawn(stack_traced(actor, "actor", trace_sender));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        {
+            let (_, report) = manager
+                .capture_all(Duration::from_secs(1))
+                .await
+                .exactly_one()
+                .ok()
+                .unwrap();
+            println!("{}", &*report);
+
+            assert_eq!(
+                report_lines(&report.report),
+                [
+                    "actor",
+                    "  join child",
+                    "  [spawned] child",
+                    "    join grandchild",
+                    "    [spawned] grandchild",
+                    "      grandchild sleep",
+                ]
+            );
+
+            let tree = report.tree.as_ref().unwrap();
+            let child = &tree.spawned[0];
+            assert_eq!(child.span, "child");
+            let grandchild = &child.spawned[0];
+            assert_eq!(grandchild.span, "grandchild");
+            assert_eq!(grandchild.children[0].span, "grandchild sleep");
         }
+
+        actor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_span_watchdog() {
+        let slow_spans = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = TraceConfig {
+            report_interval: None,
+            slow_threshold: Duration::from_millis(100),
+            on_slow_span: Some({
+                let slow_spans = slow_spans.clone();
+                Arc::new(move |slow_span: &SlowSpan| {
+                    slow_spans
+                        .lock()
+                        .unwrap()
+                        .push(slow_span.path.iter().join(" -> "))
+                })
+            }),
+            ..Default::default()
+        };
+        let (watch_tx, _watch_rx) = trace_channel();
+
+        let actor = async {
+            let fast = sleep(20).stack_trace("fast");
+            let slow = sleep(300).stack_trace("slow");
+            let check = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let report = with_context(|c| c.to_report()).report;
+                assert!(report.contains("  join [!!! "), "{report}");
+                assert!(report.contains("    slow [!!! "), "{report}");
+            };
+            futures::future::join3(fast, slow, check)
+                .stack_trace("join")
+                .await;
+        };
+        stack_traced_with_config(actor, "actor", watch_tx, config).await;
+
+        // Each slow span is reported exactly once.
+        let slow_spans = slow_spans
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .sorted()
+            .collect_vec();
+        assert_eq!(slow_spans, ["actor -> join", "actor -> join -> slow"]);
     }
 
-    #[stream(item = ())]
-    async fn stream2() {
-        sleep(200).await;
-        yield;
-        join_all([
-            sleep(400).stack_trace("sleep nested 400"),
-            sleep(600).stack_trace("sleep nested 600"),
-        ])
-        .stack_trace("sleep nested another in stream 2")
+    #[tokio::test]
+    async fn test_detached_subtree() {
+        let (watch_tx, _watch_rx) = trace_channel();
+
+        let work = async {
+            let mut fut = sleep(300).stack_trace("fut").boxed();
+
+            // Poll `fut` under the `select` span.
+            futures::future::select(sleep(50).stack_trace("sleep").boxed(), &mut fut)
+                .stack_trace("select")
+                .await;
+
+            // The `select` span is closed, so `fut` is detached with the elapsed time preserved.
+            let report = with_context(|c| c.to_report());
+            let lines = report_lines(&report.report);
+            assert_eq!(lines[0], "work");
+            assert!(lines[1].starts_with("[Detached "), "{}", report.report);
+            assert_eq!(lines[2], "  fut");
+            let detached = report.detached.iter().exactly_one().unwrap();
+            assert_eq!(detached.span, "fut");
+            assert_eq!(detached.parent_id, None);
+            assert!(detached.elapsed >= Duration::from_millis(50));
+
+            // Poll `fut` under the root `work` span, and it'll be re-attached.
+            assert!(futures::poll!(&mut fut).is_pending());
+            let report = with_context(|c| c.to_report());
+            assert_eq!(report_lines(&report.report), ["work", "  fut"]);
+            assert!(report.detached.is_empty());
+
+            fut.await;
+            assert_eq!(with_context(|c| c.active_node_count()), 1);
+        };
+
+        stack_traced(work, "work", watch_tx).await;
+    }
+
+    #[tokio::test]
+    async fn test_stack_trace_stream() {
+        let (watch_tx, _watch_rx) = trace_channel();
+
+        let work = async {
+            let mut stream = stream2().stack_trace_stream("stream2").boxed();
+            assert!(futures::poll!(stream.next()).is_pending());
+            let report = with_context(|c| c.to_report());
+            assert_eq!(
+                report_lines(&report.report),
+                ["work", "  stream2 (items: 0)"]
+            );
+
+            // The span is parked as a detached subtree while the stream is idle.
+            assert_eq!(stream.next().await, Some(()));
+            let report = with_context(|c| c.to_report());
+            let lines = report_lines(&report.report);
+            assert_eq!(lines[0], "work");
+            assert!(lines[1].starts_with("[Detached "), "{lines:?}");
+            assert_eq!(lines[2], "  stream2 (items: 1)");
+            assert_eq!(report.detached.len(), 1);
+            assert_eq!(report.detached[0].items, Some(1));
+
+            // The same span is re-attached and restarted for the next item.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(futures::poll!(stream.next()).is_pending());
+            let report = with_context(|c| c.to_report());
+            assert_eq!(
+                report_lines(&report.report),
+                [
+                    "work",
+                    "  stream2 (items: 1)",
+                    "    sleep nested another in stream 2",
+                    "      sleep nested 400",
+                    "      sleep nested 600",
+                ]
+            );
+            let stream_node = &report.tree.as_ref().unwrap().children[0];
+            assert_eq!(stream_node.items, Some(1));
+            assert!(stream_node.elapsed < Duration::from_millis(100));
+
+            assert_eq!(stream.next().await, Some(()));
+            assert_eq!(stream.next().await, None);
+            assert_eq!(with_context(|c| c.active_node_count()), 1);
+        };
+
+        stack_traced(work, "work", watch_tx).await;
+    }
+
+    #[tokio::test]
+    async fn test_http_service() {
+        let manager = Arc::new(tokio::sync::Mutex::new(StackTraceManager::default()));
+        let trace_sender = manager.lock().await.register("actor");
+
+        let actor = async {
+            let slow = sleep(400).stack_trace("slow");
+            let fast = async {
+                sleep(150).await;
+                sleep(250).stack_trace("fast").await;
+            };
+            futures::future::join(slow, fast).stack_trace("join").await;
+        };
+        let config = TraceConfig {
+            slow_threshold: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let actor = tokio::spawn(stack_traced_with_config(
+            actor,
+            "actor",
+            trace_sender,
+            config,
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let get = |uri: &'static str| {
+            let manager = manager.clone();
+            async move {
+                let req = hyper::Request::get(uri).body(hyper::Body::empty()).unwrap();
+                let res = http::handle(manager, req).await.unwrap();
+                let status = res.status();
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                // Strip the captured time and the elapsed time.
+                let lines = report_lines(&body)
+                    .into_iter()
+                    .filter(|l| !l.starts_with("[captured "))
+                    .map(str::to_owned)
+                    .collect_vec();
+                (status, body, lines)
+            }
+        };
+
+        let (_, body, lines) = get("/").await;
+        assert_eq!(
+            lines,
+            ["[actor]", "actor", "  join", "    fast", "    slow", ""],
+            "{body}"
+        );
+
+        // A task never responding should not block the requests for other tasks.
+        let _stuck = manager.lock().await.register("stuck");
+        let start = Instant::now();
+        let (_, body, lines) = get("/?key=actor&min_elapsed_ms=100").await;
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(
+            lines,
+            ["[actor]", "actor", "  join", "    slow", ""],
+            "{body}"
+        );
+        // The slow spans are still marked after filtering.
+        assert!(body.contains("    slow [!!! "), "{body}");
+
+        let (_, body, _) = get("/json?max_depth=1").await;
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let tree = &json["actor"]["tree"];
+        assert_eq!(tree["children"][0]["span"], "join");
+        assert_eq!(tree["children"][0]["children"], serde_json::json!([]));
+
+        let (status, _, _) = get("/?key=not-exist").await;
+        assert_eq!(status, hyper::StatusCode::NOT_FOUND);
+
+        actor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_span_fields() {
+        run(async {
+            let copy = async {
+                for i in 1..=3u64 {
+                    sleep(50).await;
+                    record_field("bytes", i * 100);
+                }
+            }
+            .stack_trace_with_fields("copy", [("epoch", 233u64.into()), ("actor", "a".into())]);
+
+            let check = async {
+                sleep(75).await;
+                let report = with_context(|c| c.to_report());
+                assert_eq!(
+                    report_lines(&report.report),
+                    ["root", "  copy {actor=a, bytes=100, epoch=233}"]
+                );
+                let copy = &report.tree.as_ref().unwrap().children[0];
+                assert_eq!(copy.fields["bytes"], FieldValue::U64(100));
+                assert_eq!(
+                    report.to_json()["tree"]["children"][0]["fields"]["actor"],
+                    "a"
+                );
+
+                let filtered = report.filtered_by_field("epoch", "233");
+                assert_eq!(
+                    report_lines(&filtered.report),
+                    ["root", "  copy {actor=a, bytes=100, epoch=233}"]
+                );
+                let filtered = report.filtered_by_field("epoch", "234");
+                assert_eq!(report_lines(&filtered.report), ["root"]);
+            };
+
+            futures::future::join(copy, check).await;
+        })
         .await;
-        yield;
-    }
-
-    async fn hello() {
-        async move {
-            // Join
-            join_all([
-                sleep(1000).boxed().stack_trace(format!("sleep {}", 1000)),
-                sleep(2000).boxed().stack_trace("sleep 2000"),
-                sleep_nested().boxed().stack_trace("sleep nested"),
-                multi_sleep().boxed().stack_trace("multi sleep"),
-            ])
-            .await;
+    }
+
+    #[tokio::test]
+    async fn test_tracing_spans() {
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::registry::LookupSpan;
+
+        /// Records the spans as `parent -> span` when opened and `close span` when closed.
+        #[derive(Clone, Default)]
+        struct RecordLayer(Arc<Mutex<Vec<String>>>);
+
+        struct SpanName(String);
+
+        impl<S> tracing_subscriber::Layer<S> for RecordLayer
+        where
+            S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+        {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                id: &tracing::span::Id,
+                ctx: Context<'_, S>,
+            ) {
+                let mut name = String::new();
+                attrs.record(&mut |field: &tracing::field::Field, value: &dyn Debug| {
+                    if field.name() == "span" {
+                        name = format!("{:?}", value);
+                    }
+                });
+                let span = ctx.span(id).unwrap();
+                let parent = span
+                    .parent()
+                    .and_then(|p| p.extensions().get::<SpanName>().map(|n| n.0.clone()))
+                    .unwrap_or_default();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} -> {}", parent, name));
+                span.extensions_mut().insert(SpanName(name));
+            }
+
+            fn on_close(&self, id: tracing::span::Id, ctx: Context<'_, S>) {
+                let span = ctx.span(&id).unwrap();
+                let name = span.extensions().get::<SpanName>().unwrap().0.clone();
+                self.0.lock().unwrap().push(format!("close {}", name));
+            }
+        }
+
+        let layer = RecordLayer::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(layer.clone()));
 
-            // Join another
-            join_all([
-                sleep(1200).stack_trace("sleep 1200"),
-                sleep(2200).stack_trace("sleep 2200"),
-            ])
+        let config = TraceConfig {
+            tracing_spans: true,
+            ..Default::default()
+        };
+        let (watch_tx, _watch_rx) = trace_channel();
+        let work = async {
+            futures::future::select(
+                sleep(50).stack_trace("sleep").boxed(),
+                sleep(1000).stack_trace("cancelled").boxed(),
+            )
+            .stack_trace("select")
             .await;
+        };
+        stack_traced_with_config(work, "root", watch_tx, config).await;
+
+        assert_eq!(
+            *layer.0.lock().unwrap(),
+            [
+                " -> root",
+                "root -> select",
+                "select -> sleep",
+                "select -> cancelled",
+                "close sleep",
+                // The `select` span is closed after its children in the subscriber.
+                "close cancelled",
+                "close select",
+                "close root",
+            ]
+        );
+    }
 
-            // Cancel
-            select_all([
-                sleep(666).boxed().stack_trace("sleep 666"),
-                sleep_nested()
-                    .boxed()
-                    .stack_trace("sleep nested (should be cancelled)"),
-            ])
+    #[tokio::test]
+    async fn test_span_stats() {
+        run(async {
+            for time in [20, 30, 40] {
+                sleep(time).stack_trace("sleep").await;
+            }
+            futures::future::select(
+                sleep(20).stack_trace("fast").boxed(),
+                sleep(1000).stack_trace("cancelled").boxed(),
+            )
             .await;
 
-            // Check whether cleaned up
-            sleep(233).stack_trace("sleep 233").await;
+            let report = with_context(|c| c.to_report());
+            let stats = &report.stats["sleep"];
+            assert_eq!(stats.count, 3);
+            assert!(stats.total >= Duration::from_millis(90), "{stats:?}");
+            assert!(stats.max >= Duration::from_millis(40), "{stats:?}");
+            assert!(stats.mean() >= Duration::from_millis(30), "{stats:?}");
+            assert_eq!(stats.histogram.iter().sum::<u64>(), 3);
+            assert_eq!(report.stats["fast"].count, 1);
+            // The cancelled spans are not completed.
+            assert!(!report.stats.contains_key("cancelled"));
+            assert_eq!(report.to_json()["stats"]["sleep"]["count"], 3);
+            // Rendered after the tree.
+            assert!(
+                report
+                    .to_string()
+                    .contains("[stats]\n  fast: count 1, mean "),
+                "{report}"
+            );
+        })
+        .await;
 
-            // Check stream next drop
-            {
-                let mut stream1 = stream1().fuse().boxed();
-                let mut stream2 = stream2().fuse().boxed();
-                let mut count = 0;
-
-                'outer: loop {
-                    tokio::select! {
-                        _ = stream1.next().stack_trace(format!("stream1 next {count}")) => {},
-                        r = stream2.next().stack_trace(format!("stream2 next {count}")) => {
-                            if r.is_none() { break 'outer }
-                        },
+        // Too many spans for `u32`.
+        let stats = SpanStats {
+            count: 1 << 32,
+            total: Duration::from_secs(3 << 32),
+            ..Default::default()
+        };
+        assert_eq!(stats.mean(), Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_max_stats() {
+        let (watch_tx, _watch_rx) = trace_channel();
+        let config = TraceConfig {
+            max_stats: 2,
+            ..Default::default()
+        };
+        let work = async {
+            for i in 0..5 {
+                async {}.stack_trace(format!("span {i}")).await;
+            }
+            async {}.stack_trace("span 0").await;
+
+            let report = with_context(|c| c.to_report());
+            assert_eq!(
+                report.stats.keys().collect_vec(),
+                ["<others>", "span 0", "span 1"]
+            );
+            assert_eq!(report.stats["span 0"].count, 2);
+            assert_eq!(report.stats[SpanStats::OTHERS].count, 3);
+        };
+        stack_traced_with_config(work, "root", watch_tx, config).await;
+    }
+
+    #[tokio::test]
+    async fn test_max_nodes() {
+        let config = TraceConfig {
+            max_nodes: Some(8),
+            ..Default::default()
+        };
+        let (watch_tx, _watch_rx) = trace_channel();
+        let work = async {
+            let sleeps = (0..20)
+                .map(|i| sleep(100 + i * 10).stack_trace(format!("sleep {i:02}")))
+                .collect_vec();
+            let check = async {
+                sleep(50).await;
+                let report = with_context(|c| c.to_report());
+                assert!(report.arena.active_nodes <= 8, "{report}");
+                assert_eq!(
+                    report.arena.active_nodes,
+                    with_context(|c| c.active_node_count())
+                );
+                // The slots of the collapsed nodes are reused.
+                assert!(report.arena.slots <= 9, "{:?}", report.arena);
+                assert_eq!(report.arena.collapsed, 15);
+
+                // The oldest leaves are collapsed.
+                let lines = report_lines(&report.report);
+                assert_eq!(lines[..3], ["root", "  join", "    check"], "{report}");
+                assert_eq!(
+                    lines[3..8],
+                    [
+                        "    sleep 15",
+                        "    sleep 16",
+                        "    sleep 17",
+                        "    sleep 18",
+                        "    sleep 19"
+                    ],
+                    "{report}"
+                );
+                assert_eq!(lines[8], "    ... 15 more", "{report}");
+                assert_eq!(report.tree.unwrap().children[0].collapsed, 15);
+            };
+            futures::future::join(join_all(sleeps), check.stack_trace("check"))
+                .stack_trace("join")
+                .await;
+
+            let report = with_context(|c| c.to_report());
+            assert_eq!(report.arena.collapsed, 0, "{report}");
+            assert_cleaned_up();
+        };
+        stack_traced_with_config(work, "root", watch_tx, config).await;
+    }
+
+    #[tokio::test]
+    async fn test_max_nodes_keep_state() {
+        let slow_spans = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = TraceConfig {
+            slow_threshold: Duration::from_millis(100),
+            on_slow_span: Some({
+                let slow_spans = slow_spans.clone();
+                Arc::new(move |slow_span: &SlowSpan| {
+                    slow_spans
+                        .lock()
+                        .unwrap()
+                        .push(slow_span.path.last().unwrap().to_string())
+                })
+            }),
+            max_nodes: Some(4),
+            ..Default::default()
+        };
+        let (watch_tx, _watch_rx) = trace_channel();
+        let work = async {
+            let mut futures = (0..6)
+                .map(|i| {
+                    sleep(300)
+                        .stack_trace_with_fields(format!("sleep {i}"), [("i", i.into())])
+                        .boxed()
+                })
+                .collect_vec();
+            // Wake up `join_all` frequently, so that all the spans are collapsed and pushed back
+            // in turn.
+            futures.push(
+                async {
+                    for _ in 0..12 {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                    let report = with_context(|c| c.to_report());
+                    assert!(report.arena.active_nodes <= 4, "{report}");
+                    // The fields are kept across collapsing.
+                    let tree = report.tree.unwrap();
+                    for node in &tree.children[0].children {
+                        assert!(node.fields.contains_key("i"), "{node:?}");
                     }
-                    count += 1;
                 }
+                .boxed(),
+            );
+            join_all(futures).stack_trace("join").await;
+            assert_cleaned_up();
+        };
+        stack_traced_with_config(work, "root", watch_tx, config).await;
+
+        // Reported once for each span, since the state is kept across collapsing.
+        let slow_spans = slow_spans
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            slow_spans,
+            ["join", "sleep 0", "sleep 1", "sleep 2", "sleep 3", "sleep 4", "sleep 5"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_current_span_path() {
+        assert_eq!(current_span_path(), None);
+        run(async {
+            async {
+                async {
+                    let expected = ["root", "outer", "inner"].map(str::to_owned);
+                    assert_eq!(current_span_path().unwrap(), expected);
+                    // Also read through the accessor registered for the errors.
+                    assert_eq!(arena_path::current_span_path().unwrap(), expected);
+                }
+                .stack_trace("inner")
+                .await;
+                assert_eq!(current_span_path().unwrap(), ["root", "outer"]);
             }
+            .stack_trace("outer")
+            .await;
+        })
+        .await;
+    }
 
-            // Check whether cleaned up
-            sleep(233).stack_trace("sleep 233").await;
+    #[tokio::test]
+    async fn test_migrate_context() {
+        let mut manager = StackTraceManager::default();
+        let a_tx = manager.register("actor a");
+        let b_tx = manager.register("actor b");
+        let (fut_tx, fut_rx) = futures::channel::oneshot::channel();
 
-            // TODO: add tests on sending the future to another task or context.
-        }
-        .stack_trace("hello")
-        .await;
+        let actor_a = stack_traced(
+            async move {
+                let mut fut = futures::future::join(
+                    async {
+                        record_field("recorded", 1u64);
+                        sleep(300).await
+                    },
+                    sleep(300).stack_trace("inner 2"),
+                )
+                .stack_trace_with_fields("migrated", [("initial", 0u64.into())])
+                .boxed();
+                assert!(futures::poll!(&mut fut).is_pending());
+                fut_tx.send(fut).ok().unwrap();
+
+                sleep(200).stack_trace("sleep in a").await;
+                // The migrated nodes are cleaned up.
+                assert_eq!(with_context(|c| c.active_node_count()), 1);
+            },
+            "actor a",
+            a_tx,
+        );
+
+        let actor_b = stack_traced(
+            async move {
+                let fut = fut_rx.stack_trace("recv").await.unwrap();
+                fut.stack_trace("await migrated").await;
+            },
+            "actor b",
+            b_tx,
+        );
+
+        let checker = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let reports: HashMap<_, _> =
+                manager.capture_all(Duration::from_secs(1)).await.collect();
+
+            let report = &reports[&"actor a"];
+            assert_eq!(
+                report_lines(&report.report),
+                ["actor a", "  sleep in a"],
+                "{}",
+                report.report
+            );
+
+            let report = &reports[&"actor b"];
+            assert_eq!(
+                report_lines(&report.report),
+                [
+                    "actor b",
+                    "  await migrated",
+                    "    migrated {initial=0, recorded=1}",
+                    "      inner 2"
+                ],
+                "{}",
+                report.report
+            );
+            // The elapsed time and the recorded fields are preserved after migration.
+            let migrated = &report.tree.as_ref().unwrap().children[0].children[0];
+            assert!(migrated.elapsed >= Duration::from_millis(50));
+            assert_eq!(migrated.fields["recorded"], FieldValue::U64(1));
+        };
 
-        // Aborted futures have been cleaned up. There should only be a single active node of root.
-        assert_eq!(with_context(|c| c.active_node_count()), 1);
+        tokio::join!(actor_a, actor_b, checker);
+    }
+
+    // Run in a separate process since the switch is global.
+    rusty_fork_test! {
+        #[test]
+        fn test_stack_trace_disabled_at_runtime() {
+            set_stack_trace_enabled(false);
+
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let (watch_tx, _watch_rx) = trace_channel();
+
+            let work = async {
+                let mut fut = std::pin::pin!(sleep(100).stack_trace("sleep"));
+                assert!(futures::poll!(fut.as_mut()).is_pending());
+                // No span is pushed for the future.
+                assert_eq!(with_context(|c| c.active_node_count()), 1);
+                fut.await;
+            };
+            runtime.block_on(stack_traced(work, "work", watch_tx));
+        }
     }
 
     #[tokio::test]
     async fn test_stack_trace_display() {
-        let (watch_tx, mut watch_rx) = watch::channel(Default::default());
+        let (watch_tx, mut watch_rx) = trace_channel();
 
         let collector = tokio::spawn(async move {
-            while watch_rx.changed().await.is_ok() {
+            loop {
+                watch_rx.request();
+                if watch_rx.changed().await.is_err() {
+                    break;
+                }
                 println!("{}", &*watch_rx.borrow());
+                tokio::time::sleep(Duration::from_millis(50)).await;
             }
         });
 
-        stack_traced(hello(), "actor 233", watch_tx, Duration::from_millis(50)).await;
+        stack_traced(hello(), "actor 233", watch_tx).await;
 
         collector.await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_find_deadlocks() {
+        let mut manager = StackTraceManager::default();
+        let lock_a = Arc::new(tokio::sync::Mutex::new(()));
+        let lock_b = Arc::new(tokio::sync::Mutex::new(()));
+
+        let actor = |first: Arc<tokio::sync::Mutex<()>>,
+                     first_id: &'static str,
+                     second: Arc<tokio::sync::Mutex<()>>,
+                     second_id: &'static str| async move {
+            let _first = first.lock().await;
+            let _held = hold_resource(first_id);
+            sleep(50).await;
+            let _second = second
+                .lock()
+                .stack_trace_waiting_on(format!("lock {second_id}"), second_id)
+                .stack_trace("critical section")
+                .await;
+        };
+        let a = tokio::spawn(stack_traced(
+            actor(lock_a.clone(), "a", lock_b.clone(), "b"),
+            "actor a",
+            manager.register("actor a"),
+        ));
+        let b = tokio::spawn(stack_traced(
+            actor(lock_b.clone(), "b", lock_a.clone(), "a"),
+            "actor b",
+            manager.register("actor b"),
+        ));
+        // Waiting on a resource held by no one is not a deadlock.
+        let c = tokio::spawn(stack_traced(
+            std::future::pending::<()>().stack_trace_waiting_on("paused", "valve"),
+            "actor c",
+            manager.register("actor c"),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let _ = manager.capture_all(Duration::from_secs(1)).await;
+        let deadlocks = manager.find_deadlocks();
+        let [deadlock] = &deadlocks[..] else {
+            panic!("{deadlocks:?}")
+        };
+        let cycle = deadlock
+            .cycle
+            .iter()
+            .map(|w| (w.task, w.resource.as_str(), w.holder, w.path.join(" -> ")))
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            cycle,
+            [
+                (
+                    "actor a",
+                    "b",
+                    "actor b",
+                    "actor a -> critical section -> lock b".to_owned()
+                ),
+                (
+                    "actor b",
+                    "a",
+                    "actor a",
+                    "actor b -> critical section -> lock a".to_owned()
+                ),
+            ]
+        );
+        let report = deadlock.to_string();
+        assert!(report.starts_with("deadlock among 2 tasks:"), "{report}");
+
+        // The resources are released once the tasks are aborted.
+        a.abort();
+        c.abort();
+        let _ = b.await;
+        let _ = manager.capture_all(Duration::from_secs(1)).await;
+        assert!(manager.find_deadlocks().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_find_deadlocks_spawned() {
+        let mut manager = StackTraceManager::default();
+        let lock_a = Arc::new(tokio::sync::Mutex::new(()));
+        let lock_b = Arc::new(tokio::sync::Mutex::new(()));
+
+        // The lock `a` is held by the task spawned by actor a, which waits on `b` then.
+        let a = {
+            let (lock_a, lock_b) = (lock_a.clone(), lock_b.clone());
+            let child = async move {
+                let _a = lock_a.lock().await;
+                let _held = hold_resource("a");
+                sleep(50).await;
+                let _b = lock_b.lock().stack_trace_waiting_on("lock b", "b").await;
+            };
+            tokio::spawn(stack_traced(
+                async move {
+                    spawn_stack_traced(child, "child")
+                        .stack_trace("join child")
+                        .await
+                        .unwrap();
+                },
+                "actor a",
+                manager.register("actor a"),
+            ))
+        };
+        let b = tokio::spawn(stack_traced(
+            async move {
+                let _b = lock_b.lock().await;
+                let _held = hold_resource("b");
+                sleep(50).await;
+                let _a = lock_a.lock().stack_trace_waiting_on("lock a", "a").await;
+            },
+            "actor b",
+            manager.register("actor b"),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let _ = manager.capture_all(Duration::from_secs(1)).await;
+        let deadlocks = manager.find_deadlocks();
+        let [deadlock] = &deadlocks[..] else {
+            panic!("{deadlocks:?}")
+        };
+        let cycle = deadlock
+            .cycle
+            .iter()
+            .map(|w| (w.task, w.resource.as_str(), w.holder, w.path.join(" -> ")))
+            .sorted()
+            .collect_vec();
+        assert_eq!(
+            cycle,
+            [
+                (
+                    "actor a",
+                    "b",
+                    "actor b",
+                    "actor a -> child -> lock b".to_owned()
+                ),
+                ("actor b", "a", "actor a", "actor b -> lock a".to_owned()),
+            ]
+        );
+
+        a.abort();
+        b.abort();
+    }
 }
 
 fn main() {}
@@ -1,19 +1,126 @@
 #![feature(error_generic_member_access)]
 #![feature(error_iter)]
 
+use boxed_error_derive::ErrorCode;
 use thiserror::Error;
 use traced::Traced;
 
+#[path = "../arena_path.rs"]
+mod arena_path;
+#[path = "../backtrace_fmt.rs"]
+mod backtrace_fmt;
+#[path = "../error_code.rs"]
+mod error_code;
+
 mod traced {
     use std::{
         backtrace::{Backtrace, BacktraceStatus},
         error::{request_ref, Request},
+        sync::OnceLock,
     };
     use thiserror::Error;
 
+    use crate::backtrace_fmt::BacktraceFormatter;
+
+    static BACKTRACE_FORMATTER: OnceLock<BacktraceFormatter> = OnceLock::new();
+
+    /// Set the formatter for the backtraces in the `Debug` output, e.g., with more frames allowed
+    /// or denied. Returns the formatter back if already set or used.
+    pub fn set_backtrace_formatter(
+        formatter: BacktraceFormatter,
+    ) -> Result<(), BacktraceFormatter> {
+        BACKTRACE_FORMATTER.set(formatter)
+    }
+
+    /// A span in [`SpanTrace`].
+    #[derive(Debug, Clone)]
+    pub struct SpanInfo {
+        pub target: &'static str,
+        pub name: &'static str,
+        pub location: Option<(&'static str, u32)>,
+    }
+
+    /// The stack of the `tracing` spans entered when the error is created, innermost first.
+    #[derive(Debug, Clone, Default)]
+    pub struct SpanTrace(pub Vec<SpanInfo>);
+
+    impl SpanTrace {
+        /// Capture the spans if the current subscriber is built on `tracing_subscriber::Registry`,
+        /// or nothing otherwise.
+        pub fn capture() -> Self {
+            use tracing_subscriber::registry::LookupSpan;
+
+            let Some(id) = tracing::Span::current().id() else {
+                return Self::default();
+            };
+            tracing::dispatcher::get_default(|dispatch| {
+                let Some(registry) = dispatch.downcast_ref::<tracing_subscriber::Registry>() else {
+                    return Self::default();
+                };
+                let Some(span) = registry.span(&id) else {
+                    return Self::default();
+                };
+                let spans = span
+                    .scope()
+                    .map(|span| {
+                        let metadata = span.metadata();
+                        SpanInfo {
+                            target: metadata.target(),
+                            name: metadata.name(),
+                            location: metadata.file().zip(metadata.line()),
+                        }
+                    })
+                    .collect();
+                Self(spans)
+            })
+        }
+    }
+
+    impl std::fmt::Display for SpanTrace {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            for (i, span) in self.0.iter().enumerate() {
+                if i > 0 {
+                    writeln!(f)?;
+                }
+                write!(f, "{:>4}: {}::{}", i, span.target, span.name)?;
+                if let Some((file, line)) = span.location {
+                    write!(f, "\n             at {}:{}", file, line)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// The path of the spans in the `TRACE_CONTEXT` of `arena_stack` being polled when the error
+    /// is created, from the root.
+    #[derive(Debug, Clone, Default)]
+    pub struct AsyncStackTrace(pub Vec<String>);
+
+    impl AsyncStackTrace {
+        /// Capture the path if in a traced context, or nothing otherwise, e.g., if the backend is
+        /// not included in this bin.
+        pub fn capture() -> Self {
+            Self(crate::arena_path::current_span_path().unwrap_or_default())
+        }
+    }
+
+    impl std::fmt::Display for AsyncStackTrace {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            for (depth, span) in self.0.iter().enumerate() {
+                if depth > 0 {
+                    writeln!(f)?;
+                }
+                write!(f, "{}{}", "  ".repeat(depth + 1), span)?;
+            }
+            Ok(())
+        }
+    }
+
     struct Inner<E> {
         error: E,
         backtrace: Backtrace,
+        span_trace: SpanTrace,
+        async_stack_trace: AsyncStackTrace,
     }
 
     impl<E> Inner<E>
@@ -29,8 +136,24 @@ mod traced {
             } else {
                 Backtrace::capture()
             };
+            // Like the backtrace, the traces are captured only once at the innermost error.
+            let span_trace = if request_ref::<SpanTrace>(&error).is_some() {
+                SpanTrace::default()
+            } else {
+                SpanTrace::capture()
+            };
+            let async_stack_trace = if request_ref::<AsyncStackTrace>(&error).is_some() {
+                AsyncStackTrace::default()
+            } else {
+                AsyncStackTrace::capture()
+            };
 
-            Self { error, backtrace }
+            Self {
+                error,
+                backtrace,
+                span_trace,
+                async_stack_trace,
+            }
         }
 
         fn causes(&self) -> impl Iterator<Item = &(dyn std::error::Error + 'static)> {
@@ -48,9 +171,16 @@ mod traced {
         }
 
         fn provide<'a>(&'a self, request: &mut std::error::Request<'a>) {
+            crate::error_code::provide(&self.error, request);
             if let BacktraceStatus::Captured = self.backtrace.status() {
                 request.provide_ref::<Backtrace>(&self.backtrace);
             }
+            if !self.span_trace.0.is_empty() {
+                request.provide_ref::<SpanTrace>(&self.span_trace);
+            }
+            if !self.async_stack_trace.0.is_empty() {
+                request.provide_ref::<AsyncStackTrace>(&self.async_stack_trace);
+            }
             E::provide(&self.error, request);
         }
     }
@@ -88,8 +218,22 @@ mod traced {
                 }
             }
 
+            if let Some(span_trace) = request_ref::<SpanTrace>(self) {
+                write!(f, "\n\nSpan Trace:\n{}", span_trace)?;
+            }
+
+            if let Some(async_stack_trace) = request_ref::<AsyncStackTrace>(self) {
+                write!(f, "\n\nAsync Stack Trace:\n{}", async_stack_trace)?;
+            }
+
+            // Print the whole backtrace with `{:#?}`, or the pretty one otherwise.
             if let Some(backtrace) = request_ref::<Backtrace>(self) {
-                writeln!(f, "\n\nStack Backtrace:\n{}", backtrace)?;
+                if f.alternate() {
+                    writeln!(f, "\n\nStack Backtrace:\n{}", backtrace)?;
+                } else {
+                    let formatter = BACKTRACE_FORMATTER.get_or_init(Default::default);
+                    writeln!(f, "\n\nStack Backtrace:\n{}", formatter.display(backtrace))?;
+                }
             }
 
             Ok(())
@@ -141,21 +285,24 @@ mod traced {
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, ErrorCode)]
 enum HummockErrorInner {
     #[error("Magic number mismatch: expected {expected}, found: {found}.")]
+    #[code(1001, Storage, retryable = false)]
     MagicMismatch {
         expected: u32,
         found: u32,
         backtrace: std::backtrace::Backtrace,
     },
     #[error("Invalid format version: {0}.")]
+    #[code(1002, Storage, retryable = false)]
     InvalidFormatVersion(u32),
 }
 
 pub(crate) type HummockError = Traced<HummockErrorInner>;
 
-#[derive(Error, Debug)]
+// `Hummock` has no code, so the code of the `HummockError` is provided instead.
+#[derive(Error, Debug, ErrorCode)]
 enum StreamErrorInner {
     #[error("hummock error")]
     Hummock(
@@ -164,6 +311,7 @@ enum StreamErrorInner {
         HummockError,
     ),
     #[error("internal: {0}")]
+    #[code(2001, Stream, retryable = true)]
     InternalError(String),
 }
 
@@ -175,11 +323,13 @@ impl From<HummockError> for StreamError {
     }
 }
 
+#[tracing::instrument]
 fn hummock_inner() -> Result<(), HummockError> {
     let err = HummockErrorInner::InvalidFormatVersion(233).into();
     Err(err)
 }
 
+#[tracing::instrument]
 fn hummock() -> Result<(), HummockError> {
     hummock_inner()
 }
@@ -190,8 +340,116 @@ fn err() -> Result<(), StreamError> {
 }
 
 fn main() {
+    error_code::check_registry().expect("invalid error code registry");
+
+    // Collapse the instrumentation of `tracing` as well, but keep the entry of the runtime to see
+    // where the future is blocked on.
+    let backtrace_formatter = backtrace_fmt::BacktraceFormatter::default()
+        .deny("tracing::")
+        .deny("<tracing::")
+        .allow("tokio::runtime::runtime::Runtime::block_on");
+    traced::set_backtrace_formatter(backtrace_formatter).unwrap();
+
+    // The span trace is captured with the `Registry` of `tracing_subscriber`. The async stack
+    // trace is only captured in the tasks traced by `arena_stack`, which is not included here.
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry());
     let err = err().unwrap_err();
+
     println!("Display:\n{}\n\n", err);
     println!("Display Alternate:\n{:#}\n\n", err);
     println!("Debug:\n{:?}\n\n", err);
+
+    let code = std::error::request_ref::<error_code::ErrorCode>(&err).unwrap();
+    println!(
+        "Error Code: {} ({}, retryable: {})",
+        code, code.category, code.retryable
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::error::request_ref;
+
+    use super::*;
+    use error_code::{Category, ErrorCode};
+    use traced::{AsyncStackTrace, SpanTrace};
+
+    thread_local! {
+        /// The span path of the fake backend, as `arena_stack` is not included in this bin.
+        static SPAN_PATH: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    /// Call `f` with the span path returned by the registered fake backend.
+    fn with_span_path<R>(path: &[&str], f: impl FnOnce() -> R) -> R {
+        arena_path::register(|| SPAN_PATH.with_borrow(Clone::clone));
+        SPAN_PATH.set(Some(path.iter().map(|span| span.to_string()).collect()));
+        let r = f();
+        SPAN_PATH.set(None);
+        r
+    }
+
+    #[test]
+    fn test_capture_traces() {
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let err = with_span_path(&["root", "err"], err).unwrap_err();
+
+        // Provided by the innermost `HummockError`.
+        let span_trace = request_ref::<SpanTrace>(&err).unwrap();
+        let names: Vec<_> = span_trace.0.iter().map(|span| span.name).collect();
+        assert_eq!(names, ["hummock_inner", "hummock"]);
+        let async_stack_trace = request_ref::<AsyncStackTrace>(&err).unwrap();
+        assert_eq!(async_stack_trace.0, ["root", "err"]);
+
+        let debug = format!("{:?}", err);
+        assert!(debug.contains("Span Trace:"), "{debug}");
+        assert!(
+            debug.contains("Async Stack Trace:\n  root\n    err"),
+            "{debug}"
+        );
+    }
+
+    #[test]
+    fn test_error_code() {
+        error_code::check_registry().unwrap();
+
+        // Provided by the innermost `HummockError` since `Hummock` has no code.
+        let err = err().unwrap_err();
+        let code = request_ref::<ErrorCode>(&err).unwrap();
+        assert_eq!(code.code, 1002);
+        assert_eq!(code.name, "HummockErrorInner::InvalidFormatVersion");
+        assert_eq!(code.to_string(), "E1002");
+
+        let err: StreamError = StreamErrorInner::InternalError("oops".to_owned()).into();
+        let code = request_ref::<ErrorCode>(&err).unwrap();
+        assert_eq!(
+            (code.code, code.category, code.retryable),
+            (2001, Category::Stream, true)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_error_code() {
+        let code = |code, name| ErrorCode {
+            code,
+            category: Category::Internal,
+            retryable: false,
+            name,
+            matches: |_| false,
+        };
+        let codes = [code(1, "A::X"), code(2, "A::Y"), code(1, "B::X")];
+        let err = error_code::check_unique(&codes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error code 1 is registered for both `A::X` and `B::X`"
+        );
+        assert!(error_code::check_unique(&codes[..2]).is_ok());
+    }
+
+    #[test]
+    fn test_no_traces() {
+        let err = err().unwrap_err();
+        assert!(request_ref::<SpanTrace>(&err).is_none());
+        assert!(request_ref::<AsyncStackTrace>(&err).is_none());
+    }
 }
@@ -1,9 +1,130 @@
+#![feature(error_generic_member_access)]
+#![feature(error_iter)]
+
+use std::error::request_ref;
 use std::io::Write;
 use std::{fmt, path::Path};
 
-use error_stack::{IntoReport, ResultExt};
+use bridge::{AttachProvided, ReportError};
+use error_stack::{Report, ResultExt};
 use serde::{Deserialize, Serialize};
 
+/// Adapters between [`Report`] and the [`std::error::Error`]s passing the values with `provide`,
+/// e.g., the ones derived with `thiserror`.
+mod bridge {
+    use std::error::{request_ref, Error, Request};
+    use std::fmt;
+
+    use error_stack::iter::Frames;
+    use error_stack::{Frame, Report};
+
+    /// A [`Report`] as a [`std::error::Error`], which can be used as the source of another error.
+    ///
+    /// The attachments and the values provided by the contexts are provided, where the most recent
+    /// one is returned by `request_ref`. To get all of them, request the `dyn AnyReport` instead.
+    pub struct ReportError<C>(Report<C>);
+
+    impl<C> ReportError<C> {
+        pub fn new(report: Report<C>) -> Self {
+            Self(report)
+        }
+    }
+
+    impl<C> From<Report<C>> for ReportError<C> {
+        fn from(report: Report<C>) -> Self {
+            Self(report)
+        }
+    }
+
+    impl<C> fmt::Display for ReportError<C> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Display::fmt(&self.0, f)
+        }
+    }
+
+    impl<C> fmt::Debug for ReportError<C> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(&self.0, f)
+        }
+    }
+
+    impl<C: 'static> Error for ReportError<C> {
+        fn provide<'a>(&'a self, request: &mut Request<'a>) {
+            request.provide_ref::<dyn AnyReport>(&self.0);
+            self.0.as_error().provide(request);
+        }
+    }
+
+    /// A [`Report`] with the type of the context erased.
+    pub trait AnyReport: Send + Sync {
+        fn frames(&self) -> Frames<'_>;
+    }
+
+    impl<C> AnyReport for Report<C> {
+        fn frames(&self) -> Frames<'_> {
+            Report::frames(self)
+        }
+    }
+
+    /// Get all the values of type `T` provided by the error, including the ones in the frames of
+    /// the [`ReportError`].
+    fn provided<'a, T: 'static>(error: &'a (dyn Error + 'static)) -> Vec<&'a T> {
+        match request_ref::<dyn AnyReport>(error) {
+            Some(report) => report
+                .frames()
+                .filter_map(Frame::request_ref::<T>)
+                .collect(),
+            None => request_ref::<T>(error).into_iter().collect(),
+        }
+    }
+
+    pub trait AttachProvided {
+        /// Attach the values of type `T` provided by the context and its sources, which are not
+        /// visible in the report otherwise, since only the one returned by `request_ref` on the
+        /// context is requested by [`Report::request_ref`]. That one is not attached again.
+        ///
+        /// Note that [`Report::request_ref`] always requests the context after the attachments,
+        /// so the value of the context comes last, even if it's the outermost one. E.g., a report
+        /// with `[a, b]` requested round-trips through [`ReportError`] as `[b, a]`. Attaching it
+        /// again to keep the order would make it requested twice instead.
+        fn attach_provided<T>(self) -> Self
+        where
+            T: Clone + Send + Sync + 'static;
+    }
+
+    impl<C> AttachProvided for Report<C>
+    where
+        C: Error + Send + Sync + 'static,
+    {
+        fn attach_provided<T>(self) -> Self
+        where
+            T: Clone + Send + Sync + 'static,
+        {
+            let context: &(dyn Error + 'static) = self.current_context();
+            let visible = request_ref::<T>(context);
+            let mut values: Vec<&T> = Vec::new();
+            for error in context.sources() {
+                for value in provided::<T>(error) {
+                    // The ones forwarded by the errors in between are only attached once.
+                    let seen = visible
+                        .iter()
+                        .chain(&values)
+                        .any(|v| std::ptr::eq(*v, value));
+                    if !seen {
+                        values.push(value);
+                    }
+                }
+            }
+
+            // Attach the innermost first, so that the order is kept when requested.
+            let values: Vec<T> = values.iter().rev().map(|v| (*v).clone()).collect();
+            values
+                .into_iter()
+                .fold(self, |report, value| report.attach(value))
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     host: String,
@@ -22,6 +143,7 @@ impl fmt::Display for ParseConfigError {
 // It's also possible to implement `Error` instead.
 impl error_stack::Context for ParseConfigError {}
 
+#[derive(Debug, Clone)]
 struct Suggestion(&'static str);
 
 fn parse_config(path: impl AsRef<Path>) -> error_stack::Result<Config, ParseConfigError> {
@@ -39,13 +161,31 @@ fn parse_config(path: impl AsRef<Path>) -> error_stack::Result<Config, ParseConf
     Ok(config)
 }
 
+#[derive(thiserror::Error, Debug)]
+enum OuterError {
+    // Annotate `backtrace` to forward `provide` to the report.
+    #[error("failed to load config")]
+    Config(
+        #[from]
+        #[backtrace]
+        ReportError<ParseConfigError>,
+    ),
+    #[error("failed to start")]
+    Start(#[source] Box<OuterError>),
+}
+
+fn load_config(path: impl AsRef<Path>) -> Result<Config, OuterError> {
+    Ok(parse_config(path).map_err(ReportError::new)?)
+}
+
 fn main() {
     let e1 = parse_config("fake.json").unwrap_err();
     println!("{:?}", e1);
 
     let e2 = parse_config({
         let mut f = tempfile::NamedTempFile::new().unwrap();
-        write!(f, "{}", r#"{"host": "localhost", "pot": 8080}"#).unwrap();
+        f.write_all(br#"{"host": "localhost", "pot": 8080}"#)
+            .unwrap();
         f.into_temp_path()
     })
     .unwrap_err();
@@ -54,4 +194,67 @@ fn main() {
     for s in e2.request_ref::<Suggestion>() {
         println!("suggestion: {}", s.0);
     }
+
+    // The attachments are still available after being wrapped in a `thiserror` error.
+    let e3 = load_config("fake.json").unwrap_err();
+    println!("{}", e3);
+    if let Some(s) = request_ref::<Suggestion>(&e3) {
+        println!("suggestion: {}", s.0);
+    }
+
+    // And back to a report.
+    let e4 = Report::new(OuterError::Start(Box::new(e3))).attach_provided::<Suggestion>();
+    println!("{:?}", e4);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggestions<C>(report: &Report<C>) -> Vec<&'static str> {
+        report.request_ref::<Suggestion>().map(|s| s.0).collect()
+    }
+
+    #[test]
+    fn test_bridge() {
+        let report = parse_config("fake.json")
+            .unwrap_err()
+            .attach(Suggestion("please check the permission"));
+        assert_eq!(
+            suggestions(&report),
+            ["please check the permission", "please check the path"]
+        );
+
+        let error = OuterError::from(ReportError::new(report));
+        assert_eq!(
+            request_ref::<Suggestion>(&error).unwrap().0,
+            "please check the permission"
+        );
+
+        // Not forwarded by `Start`, only found in the sources.
+        let error = OuterError::Start(Box::new(error));
+        assert!(request_ref::<Suggestion>(&error).is_none());
+        let report = Report::new(error).attach_provided::<Suggestion>();
+        assert_eq!(
+            suggestions(&report),
+            ["please check the permission", "please check the path"]
+        );
+
+        // The one provided by the context itself is not attached again.
+        let error = load_config("fake.json").unwrap_err();
+        let report = Report::new(error).attach_provided::<Suggestion>();
+        assert_eq!(suggestions(&report), ["please check the path"]);
+
+        // But the others in the report of the context are. The one of the context is requested
+        // last, so the order is not kept after the round trip.
+        let report = parse_config("fake.json")
+            .unwrap_err()
+            .attach(Suggestion("please check the permission"));
+        let error = OuterError::from(ReportError::new(report));
+        let report = Report::new(error).attach_provided::<Suggestion>();
+        assert_eq!(
+            suggestions(&report),
+            ["please check the path", "please check the permission"]
+        );
+    }
 }
@@ -1,40 +1,135 @@
 #![feature(error_generic_member_access)]
 
+#[path = "../backtrace_fmt.rs"]
+mod backtrace_fmt;
+#[path = "../error_code.rs"]
+mod error_code;
+
 // Port from `snafu`
 mod clean {
     use std::{backtrace::Backtrace, fmt};
 
-    pub struct ReportFormatter<'a>(pub &'a dyn std::error::Error);
+    use crate::backtrace_fmt::BacktraceFormatter;
+    use crate::error_code::ErrorCode;
+
+    /// The style of the report rendered by [`ReportFormatter`].
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum ReportStyle {
+        /// The head line, followed by a numbered list of the sources and the backtrace.
+        #[default]
+        Full,
+        /// The messages joined in one line as `a: b: c`, for log lines.
+        Compact,
+        /// The messages in an indented tree, followed by the backtrace, for the terminal.
+        Tree,
+        /// A JSON object listing each error with its cleaned message and type name, for the log
+        /// pipeline. See [`source_type_name`] for how the types of the sources are named.
+        Json,
+    }
+
+    pub struct ReportFormatter<'a> {
+        error: &'a dyn std::error::Error,
+        /// The type name of `error`.
+        type_name: &'static str,
+        style: ReportStyle,
+        backtrace_formatter: BacktraceFormatter,
+    }
 
     impl<'a> fmt::Display for ReportFormatter<'a> {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            self.cleaned_error_trace(f)?;
+            match self.style {
+                ReportStyle::Full => self.cleaned_error_trace(f)?,
+                ReportStyle::Compact => return self.compact(f),
+                ReportStyle::Tree => self.tree(f)?,
+                ReportStyle::Json => return self.json(f),
+            }
 
             // Note(bugen): may gate on `alternate`.
-            if let Some(bt) = std::error::request_ref::<Backtrace>(self.0) {
-                writeln!(f, "\nBacktrace:\n{}", bt)?;
+            if let Some(bt) = self.backtrace() {
+                writeln!(f, "\nBacktrace:\n{}", self.backtrace_formatter.display(bt))?;
             }
 
             Ok(())
         }
     }
 
+    /// A message in the cleaned error chain.
+    struct CleanedMessage<'a> {
+        error: &'a dyn std::error::Error,
+        message: String,
+        cleaned: bool,
+    }
+
     impl<'a> ReportFormatter<'a> {
+        /// Creates a formatter of the [`ReportStyle::Full`] style.
+        pub fn new<E: std::error::Error>(error: &'a E) -> Self {
+            Self {
+                error,
+                type_name: std::any::type_name::<E>(),
+                style: ReportStyle::default(),
+                backtrace_formatter: BacktraceFormatter::default(),
+            }
+        }
+
+        pub fn with_style(self, style: ReportStyle) -> Self {
+            Self { style, ..self }
+        }
+
+        /// Use the given formatter for the backtrace, e.g., with more frames allowed or denied.
+        pub fn with_backtrace_formatter(self, backtrace_formatter: BacktraceFormatter) -> Self {
+            Self {
+                backtrace_formatter,
+                ..self
+            }
+        }
+
+        fn backtrace(&self) -> Option<&'a Backtrace> {
+            std::error::request_ref::<Backtrace>(self.error)
+        }
+
+        /// The code provided by the error, which is rendered before the head line like `[E1001]`.
+        fn error_code(&self) -> Option<&'a ErrorCode> {
+            std::error::request_ref::<ErrorCode>(self.error)
+        }
+
+        fn write_error_code(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.error_code() {
+                Some(code) => write!(f, "[{}] ", code),
+                None => Ok(()),
+            }
+        }
+
+        /// The de-duplicated messages of the error chain, with the empty ones skipped.
+        fn cleaned_messages(&self) -> Vec<CleanedMessage<'a>> {
+            CleanedErrorText::new(self.error)
+                .filter(|(_, message, _)| !message.is_empty())
+                .map(|(error, message, cleaned)| CleanedMessage {
+                    error,
+                    message,
+                    cleaned,
+                })
+                .collect()
+        }
+
         fn cleaned_error_trace(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
             const NOTE: char = '*';
 
-            let cleaned_messages: Vec<_> = CleanedErrorText::new(self.0)
-                .flat_map(|(_, mut msg, cleaned)| {
-                    if msg.is_empty() {
-                        None
-                    } else {
+            let cleaned_messages: Vec<_> = self
+                .cleaned_messages()
+                .into_iter()
+                .map(
+                    |CleanedMessage {
+                         mut message,
+                         cleaned,
+                         ..
+                     }| {
                         if cleaned {
-                            msg.push(' ');
-                            msg.push(NOTE);
+                            message.push(' ');
+                            message.push(NOTE);
                         }
-                        Some(msg)
-                    }
-                })
+                        message
+                    },
+                )
                 .collect();
 
             let mut visible_messages = cleaned_messages.iter();
@@ -44,6 +139,7 @@ mod clean {
                 None => return Ok(()),
             };
 
+            self.write_error_code(f)?;
             writeln!(f, "{}", head)?;
 
             match cleaned_messages.len() {
@@ -60,6 +156,94 @@ mod clean {
 
             Ok(())
         }
+
+        fn compact(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let messages = self.cleaned_messages();
+            self.write_error_code(f)?;
+            for (i, msg) in messages.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(": ")?;
+                }
+                f.write_str(&msg.message)?;
+            }
+            Ok(())
+        }
+
+        fn tree(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            for (i, msg) in self.cleaned_messages().iter().enumerate() {
+                if i == 0 {
+                    self.write_error_code(f)?;
+                    writeln!(f, "{}", msg.message)?;
+                } else {
+                    writeln!(f, "{}└─ {}", "   ".repeat(i - 1), msg.message)?;
+                }
+            }
+            Ok(())
+        }
+
+        /// The type name of the error in the chain, or `None` if it's a source of unknown type.
+        fn type_name_of(&self, error: &dyn std::error::Error) -> Option<&'static str> {
+            if std::ptr::addr_eq(error, self.error) {
+                return Some(self.type_name);
+            }
+            // Find the source by address, which is `'static` to be downcast.
+            std::iter::successors(self.error.source(), |source| source.source())
+                .find(|source| std::ptr::addr_eq(*source, error))
+                .and_then(source_type_name)
+        }
+
+        fn json(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let errors: Vec<_> = self
+                .cleaned_messages()
+                .iter()
+                .map(|msg| {
+                    serde_json::json!({
+                        "message": msg.message,
+                        "type": self.type_name_of(msg.error),
+                        "cleaned": msg.cleaned,
+                    })
+                })
+                .collect();
+            let json = serde_json::json!({
+                "code": self.error_code().map(|code| serde_json::json!({
+                    "code": code.code,
+                    "category": code.category.as_str(),
+                    "retryable": code.retryable,
+                })),
+                "errors": errors,
+                "backtrace": self
+                    .backtrace()
+                    .map(|bt| self.backtrace_formatter.display(bt).to_string()),
+            });
+            write!(f, "{}", json)
+        }
+    }
+
+    /// The type name of a source error. The types are erased in the chain, so the errors of this
+    /// crate and the common foreign ones are recognized by downcasting, and the others, e.g., the
+    /// private error types of the dependencies, are `None`.
+    pub fn source_type_name(error: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+        macro_rules! known_types {
+            ($($ty:ty),* $(,)?) => {
+                $(
+                    if error.is::<$ty>() {
+                        return Some(std::any::type_name::<$ty>());
+                    }
+                )*
+            };
+        }
+        known_types!(
+            crate::MyError,
+            std::io::Error,
+            std::fmt::Error,
+            std::num::ParseIntError,
+            std::num::ParseFloatError,
+            std::str::Utf8Error,
+            std::string::FromUtf8Error,
+            hyper::Error,
+            serde_json::Error,
+        );
+        None
     }
 
     /// An iterator over an Error and its sources that removes duplicated
@@ -90,7 +274,14 @@ mod clean {
     /// 2. Middle error text
     /// 3. Inner error text
     /// ```
-
+    ///
+    /// The source error's text is also removed if it's embedded in the middle,
+    /// along with the brackets or the separator around it:
+    ///
+    /// ```text
+    /// io error: Inner error text while reading  =>  io error while reading
+    /// failed (Inner error text)                 =>  failed
+    /// ```
     pub struct CleanedErrorText<'a>(Option<CleanedErrorTextStep<'a>>);
 
     impl<'a> CleanedErrorText<'a> {
@@ -114,13 +305,13 @@ mod clean {
                 Some(next_error) => {
                     let next_error_text = next_error.to_string();
 
-                    let cleaned_text = error_text
-                        .trim_end_matches(&next_error_text)
-                        .trim_end()
-                        .trim_end_matches(':');
-                    let cleaned = cleaned_text.len() != error_text.len();
-                    let cleaned_len = cleaned_text.len();
-                    error_text.truncate(cleaned_len);
+                    let cleaned = match remove_source_text(&error_text, &next_error_text) {
+                        Some(cleaned_text) => {
+                            error_text = cleaned_text;
+                            true
+                        }
+                        None => false,
+                    };
 
                     self.0 = Some(CleanedErrorTextStep {
                         error: next_error,
@@ -134,65 +325,70 @@ mod clean {
         }
     }
 
-    struct CleanedErrorTextStep<'a> {
-        error: &'a dyn std::error::Error,
-        error_text: String,
-    }
-
-    impl<'a> CleanedErrorTextStep<'a> {
-        fn new(error: &'a dyn std::error::Error) -> Self {
-            let error_text = error.to_string();
-            Self { error, error_text }
+    /// Remove the `source` text from the `text` of the containing error. Returns `None` if it's
+    /// not found.
+    fn remove_source_text(text: &str, source: &str) -> Option<String> {
+        if source.is_empty() {
+            return None;
         }
-    }
-}
-
-// https://github.com/rust-lang/rust/issues/117432
-mod my_box {
-    use std::ops::{Deref, DerefMut};
 
-    #[derive(Clone)]
-    pub struct MyBox<T>(Box<T>);
-
-    impl<T> MyBox<T> {
-        pub fn new(t: T) -> Self {
-            Self(Box::new(t))
+        // Most commonly, the source is appended to the end, like `outer: inner`.
+        let cleaned_text = text
+            .trim_end_matches(source)
+            .trim_end()
+            .trim_end_matches(':');
+        if cleaned_text.len() != text.len() {
+            return Some(cleaned_text.to_owned());
         }
-    }
 
-    impl<T> Deref for MyBox<T> {
-        type Target = T;
-
-        fn deref(&self) -> &Self::Target {
-            &self.0
+        // Otherwise, find it anywhere as whole words, ignoring the ASCII case since it may be
+        // capitalized differently, like `failed to connect: Connection refused`.
+        let start = text
+            .to_ascii_lowercase()
+            .rfind(&source.to_ascii_lowercase())?;
+        let (mut left, mut right) = (&text[..start], &text[start + source.len()..]);
+        let is_word_char = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+        if is_word_char(left.chars().next_back()) || is_word_char(right.chars().next()) {
+            return None;
         }
-    }
 
-    impl<T> DerefMut for MyBox<T> {
-        fn deref_mut(&mut self) -> &mut Self::Target {
-            &mut self.0
+        // Remove the brackets or quotes around it, like `failed ({source})`.
+        const PAIRS: [(char, char); 6] = [
+            ('(', ')'),
+            ('[', ']'),
+            ('{', '}'),
+            ('`', '`'),
+            ('"', '"'),
+            ('\'', '\''),
+        ];
+        if let Some((l, r)) = PAIRS
+            .iter()
+            .find_map(|&(open, close)| Some((left.strip_suffix(open)?, right.strip_prefix(close)?)))
+        {
+            (left, right) = (l, r);
         }
-    }
 
-    impl<T: std::fmt::Display> std::fmt::Display for MyBox<T> {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            self.0.fmt(f)
-        }
-    }
+        // And the separator before it, like `io error: {source} while reading`.
+        let left = left.trim_end().trim_end_matches([':', ',', '-']).trim_end();
+        let right = right.trim_start();
 
-    impl<T: std::fmt::Debug> std::fmt::Debug for MyBox<T> {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            self.0.fmt(f)
+        let mut cleaned_text = left.to_owned();
+        if !left.is_empty() && !right.is_empty() && !right.starts_with([',', '.', ';']) {
+            cleaned_text.push(' ');
         }
+        cleaned_text.push_str(right);
+        Some(cleaned_text)
     }
 
-    impl<T: std::error::Error> std::error::Error for MyBox<T> {
-        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-            T::source(&*self.0)
-        }
+    struct CleanedErrorTextStep<'a> {
+        error: &'a dyn std::error::Error,
+        error_text: String,
+    }
 
-        fn provide<'a>(&'a self, request: &mut std::error::Request<'a>) {
-            T::provide(&*self.0, request)
+    impl<'a> CleanedErrorTextStep<'a> {
+        fn new(error: &'a dyn std::error::Error) -> Self {
+            let error_text = error.to_string();
+            Self { error, error_text }
         }
     }
 }
@@ -201,10 +397,21 @@ mod my_box {
 
 use std::backtrace::Backtrace;
 
-#[derive(thiserror::Error, Debug)]
+use boxed_error_derive::{BoxedError, ErrorContext};
+
+// Derive `MyError` as a pointer-sized newtype of `MyErrorInner`, with `From` implemented for `?`
+// to work on the wrapped error, and `Error::provide` forwarded. We do not always include a
+// backtrace here, so the `backtrace` option is not used. The codes declared on the variants are
+// registered and provided with the `error_code` option. `Io` and `Uncategorized` have no code, so
+// the code of the source is provided instead, if any.
+//
+// Also derive the context extension traits, e.g., `ParseResultExt` for the `Parse` variant.
+#[derive(thiserror::Error, Debug, BoxedError, ErrorContext)]
+#[boxed_error(MyError, error_code)]
 pub enum MyErrorInner {
     // No need to include the source error in the message, but reliably maintain the source chain.
     #[error("network error")]
+    #[code(3001, Internal, retryable = true)]
     Network {
         #[from] // This will help us implement `source`.
         error: hyper::Error,
@@ -222,6 +429,7 @@ pub enum MyErrorInner {
 
     // This shows how to use `context` to construct error type in a more elegant way.
     #[error("cannot parse int from `{from}`")]
+    #[code(3002, InvalidInput, retryable = false)]
     Parse {
         #[source]
         error: std::num::ParseIntError,
@@ -229,6 +437,7 @@ pub enum MyErrorInner {
     },
 
     #[error("unsupported operation: {0}")]
+    #[code(3003, InvalidInput, retryable = false)]
     UnsupportedOperation(String),
 
     #[error(transparent)]
@@ -241,40 +450,6 @@ pub enum MyErrorInner {
     ),
 }
 
-#[derive(thiserror::Error, Debug)]
-#[error(transparent)]
-pub struct MyError(
-    // The reason for such annotations is exactly the same as `anyhow::Error`.
-    #[from]
-    #[backtrace]
-    pub my_box::MyBox<MyErrorInner>, // To make sure the size is one word.
-
-                                     // do not always include a backtrace here.
-);
-
-// For `?` to work on the wrapped `MyError`.
-impl<E> From<E> for MyError
-where
-    E: Into<MyErrorInner>,
-{
-    fn from(error: E) -> Self {
-        Self(my_box::MyBox::new(error.into()))
-    }
-}
-
-#[easy_ext::ext(ParseResultExt)]
-impl<T> Result<T, std::num::ParseIntError> {
-    pub fn context(self, from: impl Into<String>) -> Result<T, MyError> {
-        self.map_err(|error| {
-            MyErrorInner::Parse {
-                error,
-                from: from.into(),
-            }
-            .into()
-        })
-    }
-}
-
 async fn work() -> Result<(), MyError> {
     hyper::client::Client::new()
         .get(hyper::Uri::from_static("http://not-exist"))
@@ -291,6 +466,8 @@ async fn work_2() -> Result<(), MyError> {
 
 #[tokio::main]
 async fn main() {
+    error_code::check_registry().expect("invalid error code registry");
+
     let error = work().await.unwrap_err();
     print_error(&error);
 
@@ -299,6 +476,476 @@ async fn main() {
 }
 
 fn print_error(error: &MyError) {
-    // Always print the error with `snafu_clean::ReportFormatter`.
-    println!("{}", clean::ReportFormatter(error));
+    // Always print the error with `snafu_clean::ReportFormatter`, with the internals of the hyper
+    // client collapsed in the backtrace as well, but keep the entry of the runtime to see where
+    // the future is blocked on.
+    let backtrace_formatter = backtrace_fmt::BacktraceFormatter::default()
+        .deny("hyper::")
+        .deny("<hyper::")
+        .allow("tokio::runtime::runtime::Runtime::block_on");
+    println!(
+        "{}",
+        clean::ReportFormatter::new(error).with_backtrace_formatter(backtrace_formatter.clone())
+    );
+    // Or in other styles, e.g., in one line for the logs, or as JSON for the log pipeline.
+    for style in [
+        clean::ReportStyle::Compact,
+        clean::ReportStyle::Tree,
+        clean::ReportStyle::Json,
+    ] {
+        println!(
+            "{}",
+            clean::ReportFormatter::new(error)
+                .with_style(style)
+                .with_backtrace_formatter(backtrace_formatter.clone())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expect_test::{expect, Expect};
+
+    use super::*;
+    use clean::{CleanedErrorText, ReportFormatter, ReportStyle};
+
+    fn parse_error() -> MyError {
+        "not a number"
+            .parse::<i32>()
+            .context("not a number")
+            .unwrap_err()
+    }
+
+    fn io_error() -> MyError {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "file not found").into()
+    }
+
+    /// An error providing the code by hand, as the derived `provide` of `thiserror` does not.
+    #[derive(Debug)]
+    struct CodedError(std::io::Error);
+
+    static CODED_ERROR: error_code::ErrorCode = error_code::ErrorCode {
+        code: 42,
+        category: error_code::Category::Storage,
+        retryable: true,
+        name: "CodedError",
+        matches: |error| error.is::<CodedError>(),
+    };
+
+    impl std::fmt::Display for CodedError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "failed to flush")
+        }
+    }
+
+    impl std::error::Error for CodedError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+
+        fn provide<'a>(&'a self, request: &mut std::error::Request<'a>) {
+            request.provide_ref::<error_code::ErrorCode>(&CODED_ERROR);
+        }
+    }
+
+    #[test]
+    fn test_error_code() {
+        let error = CodedError(not_found());
+        let report = |style| ReportFormatter::new(&error).with_style(style).to_string();
+        assert_eq!(
+            report(ReportStyle::Full),
+            "[E0042] failed to flush\n\nCaused by this error:\n  1: file not found\n"
+        );
+        assert_eq!(
+            report(ReportStyle::Compact),
+            "[E0042] failed to flush: file not found"
+        );
+        assert_eq!(
+            report(ReportStyle::Tree),
+            "[E0042] failed to flush\n└─ file not found\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&report(ReportStyle::Json)).unwrap();
+        assert_eq!(
+            json["code"],
+            serde_json::json!({ "code": 42, "category": "storage", "retryable": true })
+        );
+
+        // Not rendered if not provided.
+        assert!(ReportFormatter::new(&io_error())
+            .to_string()
+            .starts_with("io error"));
+
+        // Registered for the variant of `MyErrorInner`, and provided through `MyError`.
+        error_code::check_registry().unwrap();
+        let error: MyError = MyErrorInner::UnsupportedOperation("drop".to_owned()).into();
+        let code = std::error::request_ref::<error_code::ErrorCode>(&error).unwrap();
+        assert_eq!(
+            (code.code, code.name),
+            (3003, "MyErrorInner::UnsupportedOperation")
+        );
+        assert_eq!(
+            ReportFormatter::new(&error)
+                .with_style(ReportStyle::Compact)
+                .to_string(),
+            "[E3003] unsupported operation: drop"
+        );
+    }
+
+    #[test]
+    fn test_compact_style() {
+        let report = |e: &MyError| {
+            ReportFormatter::new(e)
+                .with_style(ReportStyle::Compact)
+                .to_string()
+        };
+        assert_eq!(
+            report(&parse_error()),
+            "[E3002] cannot parse int from `not a number`: invalid digit found in string"
+        );
+        // The source interpolated into the message is cleaned.
+        assert_eq!(report(&io_error()), "io error: file not found");
+    }
+
+    #[test]
+    fn test_tree_style() {
+        let report = ReportFormatter::new(&parse_error())
+            .with_style(ReportStyle::Tree)
+            .to_string();
+        assert_eq!(
+            report,
+            "[E3002] cannot parse int from `not a number`\n└─ invalid digit found in string\n"
+        );
+    }
+
+    #[test]
+    fn test_json_style() {
+        let report = ReportFormatter::new(&io_error())
+            .with_style(ReportStyle::Json)
+            .to_string();
+        let json: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(
+            json["errors"],
+            serde_json::json!([
+                {
+                    "message": "io error",
+                    "type": "error_thiserror::MyError",
+                    "cleaned": true
+                },
+                {
+                    "message": "file not found",
+                    "type": "std::io::error::Error",
+                    "cleaned": false
+                },
+            ])
+        );
+
+        // The source of an unknown type is not named.
+        let report = ReportFormatter::new(&CorpusError::PartialWord(Word))
+            .with_style(ReportStyle::Json)
+            .to_string();
+        let json: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(
+            json["errors"],
+            serde_json::json!([
+                {
+                    "message": "multiple errors occurred",
+                    "type": "error_thiserror::tests::CorpusError",
+                    "cleaned": false
+                },
+                { "message": "error", "type": null, "cleaned": false },
+            ])
+        );
+    }
+
+    /// Render the cleaned messages line by line, with the cleaned ones marked with `*`.
+    fn do_test(error: &(dyn std::error::Error + 'static), expect: Expect) {
+        let lines: Vec<_> = CleanedErrorText::new(error)
+            .map(|(_, msg, cleaned)| format!("{msg}{}", if cleaned { " *" } else { "" }))
+            .collect();
+        expect.assert_eq(&lines.join("\n"));
+    }
+
+    #[derive(thiserror::Error, Debug)]
+    enum CorpusError {
+        #[error("io error: {0} while reading")]
+        Middle(#[source] std::io::Error),
+        #[error("failed ({0})")]
+        Parenthesized(#[source] std::num::ParseIntError),
+        #[error("cannot load `{0}`, skipped")]
+        Quoted(#[source] std::io::Error),
+        #[error("multiple {0}s occurred")]
+        PartialWord(#[source] Word),
+    }
+
+    #[derive(thiserror::Error, Debug)]
+    #[error("error")]
+    struct Word;
+
+    fn not_found() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "file not found")
+    }
+
+    #[test]
+    fn test_clean_thiserror() {
+        do_test(
+            &parse_error(),
+            expect![[r#"
+                cannot parse int from `not a number`
+                invalid digit found in string"#]],
+        );
+        do_test(
+            &io_error(),
+            expect![[r#"
+                io error *
+                file not found"#]],
+        );
+        do_test(
+            &CorpusError::Middle(not_found()),
+            expect![[r#"
+                io error while reading *
+                file not found"#]],
+        );
+        do_test(
+            &CorpusError::Parenthesized("".parse::<u8>().unwrap_err()),
+            expect![[r#"
+                failed *
+                cannot parse integer from empty string"#]],
+        );
+        do_test(
+            &CorpusError::Quoted(not_found()),
+            expect![[r#"
+                cannot load, skipped *
+                file not found"#]],
+        );
+        // Only whole words are matched.
+        do_test(
+            &CorpusError::PartialWord(Word),
+            expect![[r#"
+                multiple errors occurred
+                error"#]],
+        );
+    }
+
+    #[test]
+    fn test_clean_anyhow() {
+        let error = anyhow::Error::new(not_found()).context("failed to load config");
+        do_test(
+            error.as_ref(),
+            expect![[r#"
+                failed to load config
+                file not found"#]],
+        );
+
+        let error = anyhow::Error::new(not_found())
+            .context(format!("failed to load config ({}), retrying", not_found()));
+        do_test(
+            error.as_ref(),
+            expect![[r#"
+                failed to load config, retrying *
+                file not found"#]],
+        );
+
+        // Capitalized differently.
+        let error = anyhow::Error::new(std::io::Error::other("Connection reset"))
+            .context("request failed: connection reset, giving up");
+        do_test(
+            error.as_ref(),
+            expect![[r#"
+                request failed, giving up *
+                Connection reset"#]],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clean_hyper() {
+        /// A connector always refusing the connection, without touching the network.
+        #[derive(Clone)]
+        struct Refused;
+
+        impl hyper::service::Service<hyper::Uri> for Refused {
+            type Error = std::io::Error;
+            type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+            type Response = tokio::net::TcpStream;
+
+            fn poll_ready(
+                &mut self,
+                _cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<Result<(), Self::Error>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, _uri: hyper::Uri) -> Self::Future {
+                std::future::ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    "connection refused",
+                )))
+            }
+        }
+
+        let error: MyError = hyper::Client::builder()
+            .build::<_, hyper::Body>(Refused)
+            .get(hyper::Uri::from_static("http://not-exist"))
+            .await
+            .unwrap_err()
+            .into();
+        do_test(
+            &error,
+            expect![[r#"
+                network error
+                error trying to connect *
+                connection refused"#]],
+        );
+    }
+
+    #[test]
+    fn test_backtrace_formatter() {
+        use crate::backtrace_fmt::BacktraceFormatter;
+
+        let backtrace = "   0: std::backtrace::Backtrace::capture
+             at /rustc/library/std/src/backtrace.rs:296:9
+   1: <error_thiserror::MyError as core::convert::From<E>>::from
+             at ./src/bin/error_thiserror.rs:100:9
+   2: <core::result::Result<T,F> as core::ops::try_trait::FromResidual<core::result::Result<core::convert::Infallible,E>>>::from_residual
+             at /rustc/library/core/src/result.rs:1989:27
+   3: error_thiserror::work_2
+             at ./src/bin/error_thiserror.rs:200:13
+   4: <core::pin::Pin<P> as core::future::future::Future>::poll
+             at /rustc/library/core/src/future/future.rs:124:9
+   5: tokio::runtime::park::CachedParkThread::block_on
+             at /root/tokio/src/runtime/park.rs:282:63
+   6: hyper::client::Client::get
+             at /root/hyper/src/client.rs:10:5
+   7: error_thiserror::main
+             at ./src/bin/error_thiserror.rs:300:5
+   8: std::rt::lang_start
+             at /rustc/library/std/src/rt.rs:198:5
+   9: main
+  10: __libc_start_main
+  11: _start
+";
+
+        expect![[r#"
+            >  3: error_thiserror::work_2
+                         at ./src/bin/error_thiserror.rs:200:13
+                  ... 2 frames hidden
+               6: hyper::client::Client::get
+                         at /root/hyper/src/client.rs:10:5
+            >  7: error_thiserror::main
+                         at ./src/bin/error_thiserror.rs:300:5
+                  ... 1 frame hidden
+               9: main
+                  ... 2 frames hidden
+        "#]]
+        .assert_eq(
+            &BacktraceFormatter::default()
+                .display_text(backtrace)
+                .to_string(),
+        );
+
+        let formatter = BacktraceFormatter::default()
+            .allow("tokio::")
+            .deny("hyper::");
+        expect![[r#"
+            >  3: error_thiserror::work_2
+                         at ./src/bin/error_thiserror.rs:200:13
+                  ... 1 frame hidden
+               5: tokio::runtime::park::CachedParkThread::block_on
+                         at /root/tokio/src/runtime/park.rs:282:63
+                  ... 1 frame hidden
+            >  7: error_thiserror::main
+                         at ./src/bin/error_thiserror.rs:300:5
+                  ... 1 frame hidden
+               9: main
+                  ... 2 frames hidden
+        "#]]
+        .assert_eq(&formatter.display_text(backtrace).to_string());
+    }
+
+    #[test]
+    fn test_boxed_error() {
+        assert_eq!(std::mem::size_of::<MyError>(), std::mem::size_of::<usize>());
+
+        let error = io_error();
+        assert!(matches!(error.inner(), MyErrorInner::Io { .. }));
+        // The backtrace of the inner error is provided through the box.
+        assert!(std::error::request_ref::<Backtrace>(&error).is_some());
+        assert!(format!("{:?}", error).starts_with("MyError(Io {"));
+        assert!(matches!(error.into_inner(), MyErrorInner::Io { .. }));
+    }
+
+    #[test]
+    fn test_boxed_error_with_backtrace() {
+        #[derive(thiserror::Error, Debug, BoxedError)]
+        #[boxed_error(TracedError, backtrace)]
+        enum TracedErrorInner {
+            #[error("parse error")]
+            Parse(#[from] std::num::ParseIntError),
+        }
+
+        fn parse() -> Result<i32, TracedError> {
+            Ok("not a number".parse::<i32>()?)
+        }
+
+        assert_eq!(
+            std::mem::size_of::<TracedError>(),
+            std::mem::size_of::<usize>()
+        );
+        let error = parse().unwrap_err();
+        assert_eq!(error.to_string(), "parse error");
+        assert!(std::error::Error::source(&error).is_some());
+        // Provided only if captured, which depends on `RUST_BACKTRACE`.
+        let captured = error.0.backtrace.status() == std::backtrace::BacktraceStatus::Captured;
+        assert_eq!(
+            std::error::request_ref::<Backtrace>(&error).is_some(),
+            captured
+        );
+    }
+
+    #[test]
+    fn test_error_context() {
+        let error = "x".parse::<i32>().with_context(|| "x").unwrap_err();
+        assert!(matches!(
+            error.inner(),
+            MyErrorInner::Parse { from, .. } if from == "x"
+        ));
+    }
+
+    // In a separate module, so that the traits for the same source type are not ambiguous.
+    mod context {
+        use std::backtrace::Backtrace;
+
+        use boxed_error_derive::ErrorContext;
+
+        use super::not_found;
+
+        #[derive(thiserror::Error, Debug, ErrorContext)]
+        enum ReadError {
+            #[error("cannot read {len} bytes from `{path}`")]
+            Read {
+                source: std::io::Error,
+                path: String,
+                len: usize,
+                backtrace: Backtrace,
+            },
+            #[error("cannot read line {1}")]
+            Line(#[source] std::num::ParseIntError, u32),
+        }
+
+        #[test]
+        fn test_error_context_fields() {
+            let read = || -> Result<(), std::io::Error> { Err(not_found()) };
+            let error = read().context("/tmp/a", 42usize).unwrap_err();
+            assert_eq!(error.to_string(), "cannot read 42 bytes from `/tmp/a`");
+            let error = read().with_context(|| ("/tmp/b", 7usize)).unwrap_err();
+            assert_eq!(error.to_string(), "cannot read 7 bytes from `/tmp/b`");
+            let error = "".parse::<i32>().context(3u32).unwrap_err();
+            assert_eq!(error.to_string(), "cannot read line 3");
+
+            // Not evaluated if ok.
+            let ok = "1"
+                .parse::<i32>()
+                .with_context(|| -> u32 { unreachable!() });
+            assert_eq!(ok.unwrap(), 1);
+        }
+    }
 }
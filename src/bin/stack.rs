@@ -2,25 +2,32 @@
 #![feature(coroutines)]
 #![allow(clippy::declare_interior_mutable_const)]
 
+#[macro_use]
+#[path = "../trace_context.rs"]
+pub mod trace_context;
+
 fn main() {}
 
-use std::borrow::Cow;
 use std::cell::{RefCell, RefMut};
 use std::collections::{BTreeMap, HashSet};
 use std::fmt::{Debug, Write};
 use std::hash::Hash;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::Poll;
 use std::time::Instant;
 
 use futures::future::Fuse;
-use futures::{Future, FutureExt};
+use futures::Future;
+#[cfg(feature = "stack-trace")]
+use futures::FutureExt;
 use itertools::Itertools;
 use pin_project::{pin_project, pinned_drop};
 use tokio::sync::watch;
 
-pub type SpanValue = Cow<'static, str>;
+pub use trace_context::SpanValue;
+use trace_context::TraceContext;
 
 #[derive(Clone)]
 pub struct StackTreeNode {
@@ -131,17 +138,34 @@ impl StackTreeNode {
     fn clear_children(&self) {
         self.inner.borrow_mut().children.clear();
     }
+
+    /// Delete this node from the parent and clear the children, used for future aborting.
+    fn remove(&self) {
+        self.delete_from_parent_unchecked();
+        self.clear_children();
+    }
+
+    /// Count the nodes in the subtree of this node.
+    fn count(&self) -> usize {
+        1 + self
+            .inner
+            .borrow()
+            .children
+            .iter()
+            .map(Self::count)
+            .sum::<usize>()
+    }
 }
 
-// TODO: may use a better name
+/// The [`TraceContext`] backed by a tree of `Rc<RefCell>` nodes.
 #[derive(Debug)]
-pub struct TraceContext {
+pub struct RcTraceContext {
     pub root: StackTreeNode,
 
     current: StackTreeNode,
 }
 
-impl std::fmt::Display for TraceContext {
+impl std::fmt::Display for RcTraceContext {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         fn fmt_node(
             f: &mut std::fmt::Formatter<'_>,
@@ -170,7 +194,9 @@ impl std::fmt::Display for TraceContext {
     }
 }
 
-impl TraceContext {
+impl TraceContext for RcTraceContext {
+    type Node = StackTreeNode;
+
     fn new(root_span: SpanValue) -> Self {
         let root = StackTreeNode::root(root_span);
 
@@ -180,6 +206,10 @@ impl TraceContext {
         }
     }
 
+    fn current(&self) -> StackTreeNode {
+        self.current.clone()
+    }
+
     fn push(&mut self, span: SpanValue) -> StackTreeNode {
         let new_current_node = self.current.add_child(span);
         self.current = new_current_node.clone();
@@ -196,7 +226,8 @@ impl TraceContext {
         self.current = child.clone();
     }
 
-    fn pop(&mut self, child: &StackTreeNode) {
+    fn pop(&mut self) {
+        let child = self.current.clone();
         child.delete_from_parent();
         self.current = child.parent();
     }
@@ -204,15 +235,23 @@ impl TraceContext {
     fn step_out(&mut self) {
         self.current = self.current.parent();
     }
+
+    fn remove_and_detach(&mut self, node: &StackTreeNode) {
+        node.remove();
+    }
+
+    fn active_node_count(&self) -> usize {
+        self.root.count()
+    }
 }
 
 tokio::task_local! {
-    pub static TRACE_CONTEXT: RefCell<TraceContext>
+    pub static TRACE_CONTEXT: RefCell<RcTraceContext>
 }
 
-fn with_write_context<F, R>(f: F) -> R
+fn with_context<F, R>(f: F) -> R
 where
-    F: FnOnce(RefMut<TraceContext>) -> R,
+    F: FnOnce(RefMut<RcTraceContext>) -> R,
 {
     TRACE_CONTEXT.with(|trace_context| {
         let trace_context = trace_context.borrow_mut();
@@ -244,21 +283,35 @@ impl TraceContextManager {
     }
 }
 
+static STACK_TRACE_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Enable or disable the stack trace at runtime. The futures wrapped when disabled will never be
+/// traced. To eliminate the cost completely, disable the `stack-trace` feature instead.
+pub fn set_stack_trace_enabled(enabled: bool) {
+    STACK_TRACE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
 #[pin_project(PinnedDrop)]
 pub struct StackTraced<F: Future> {
     #[pin]
     inner: Fuse<F>,
 
+    /// Whether the stack trace is enabled when this future is created.
+    enabled: bool,
+
     span: Option<SpanValue>,
 
     this_node: Option<StackTreeNode>,
 }
 
 impl<F: Future> StackTraced<F> {
+    #[cfg(feature = "stack-trace")]
     fn new(inner: F, span: impl Into<SpanValue>) -> Self {
+        let enabled = STACK_TRACE_ENABLED.load(Ordering::Relaxed);
         Self {
             inner: inner.fuse(),
-            span: Some(span.into()),
+            enabled,
+            span: enabled.then(|| span.into()),
             this_node: None,
         }
     }
@@ -267,17 +320,16 @@ impl<F: Future> StackTraced<F> {
 impl<F: Future> Future for StackTraced<F> {
     type Output = F::Output;
 
-    // TODO: may disable on cfg(not(debug_assertions))
     fn poll(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
 
-        if !context_exists() {
+        if !*this.enabled || !context_exists() {
             return this.inner.poll(cx);
         }
 
-        let old_current = with_write_context(|c| c.current.clone());
+        let old_current = with_context(|c| c.current.clone());
 
-        let this_node = with_write_context(|mut c| match this.this_node {
+        let this_node = with_context(|mut c| match this.this_node {
             Some(this_node) => {
                 c.step_in(this_node);
                 this_node
@@ -289,17 +341,20 @@ impl<F: Future> Future for StackTraced<F> {
 
         let r = match this.inner.poll(cx) {
             Poll::Ready(r) => {
-                with_write_context(|mut c| c.pop(this_node));
+                with_context(|mut c| {
+                    assert_eq!(this_node, &c.current);
+                    c.pop()
+                });
                 *this.this_node = None;
                 Poll::Ready(r)
             }
             Poll::Pending => {
-                with_write_context(|mut c| c.step_out());
+                with_context(|mut c| c.step_out());
                 Poll::Pending
             }
         };
 
-        assert_eq!(old_current, with_write_context(|c| c.current.clone()));
+        assert_eq!(old_current, with_context(|c| c.current.clone()));
 
         r
     }
@@ -313,10 +368,7 @@ impl<F: Future> PinnedDrop for StackTraced<F> {
         let this = self.project();
 
         match this.this_node {
-            Some(this_node) => {
-                this_node.delete_from_parent_unchecked();
-                this_node.clear_children();
-            }
+            Some(this_node) => this_node.remove(),
             None => {} // not polled or ready
         }
     }
@@ -325,9 +377,15 @@ impl<F: Future> PinnedDrop for StackTraced<F> {
 impl<T> StackTrace for T where T: Future {}
 
 pub trait StackTrace: Future + Sized {
+    #[cfg(feature = "stack-trace")]
     fn stack_trace(self, span: impl Into<SpanValue>) -> StackTraced<Self> {
         StackTraced::new(self, span)
     }
+
+    #[cfg(not(feature = "stack-trace"))]
+    fn stack_trace(self, _span: impl Into<SpanValue>) -> Self {
+        self
+    }
 }
 
 pub async fn monitored<F: Future>(
@@ -338,7 +396,7 @@ pub async fn monitored<F: Future>(
 ) -> F::Output {
     TRACE_CONTEXT
         .scope(
-            RefCell::new(TraceContext::new(root_span.into())),
+            RefCell::new(RcTraceContext::new(root_span.into())),
             async move {
                 let monitor = async move {
                     loop {
@@ -364,140 +422,18 @@ pub async fn monitored<F: Future>(
         .await
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "stack-trace"))]
 mod tests {
-    use futures::{
-        future::{join_all, select_all},
-        StreamExt,
-    };
-    use futures_async_stream::stream;
     use tokio::sync::watch;
 
     use super::*;
 
-    async fn sleep(time: u64) {
-        tokio::time::sleep(std::time::Duration::from_millis(time)).await;
-        println!("slept {time}ms");
-    }
-
-    async fn sleep_nested() {
-        join_all([
-            sleep(1500).stack_trace("sleep nested 1500"),
-            sleep(2500).stack_trace("sleep nested 2500"),
-        ])
-        .await;
-    }
-
-    async fn sleep_nested_select() {
-        select_all([
-            sleep(1500).boxed().stack_trace("sleep nested select 1500"),
-            sleep(2500)
-                .boxed()
-                .stack_trace("sleep nested select 2500 (should be cancelled)"),
-        ])
-        .await;
+    async fn run<F: Future>(f: F) -> F::Output {
+        let (watch_tx, _watch_rx) = watch::channel(String::new());
+        monitored(f, "root", watch_tx, 1000).await
     }
 
-    async fn multi_sleep() {
-        sleep(400).await;
-
-        sleep(800).stack_trace("sleep another in multi sleep").await;
-    }
-
-    #[stream(item = ())]
-    async fn stream1() {
-        loop {
-            sleep(150).await;
-            yield;
-        }
-    }
-
-    #[stream(item = ())]
-    async fn stream2() {
-        sleep(200).await;
-        yield;
-        join_all([
-            sleep(400).stack_trace("sleep nested 400"),
-            sleep(600).stack_trace("sleep nested 600"),
-        ])
-        .stack_trace("sleep nested another in stream 2")
-        .await;
-        yield;
-    }
-
-    async fn hello() {
-        async move {
-            // Join
-            join_all([
-                sleep(1000).boxed().stack_trace(format!("sleep {}", 1000)),
-                sleep(2000).boxed().stack_trace("sleep 2000"),
-                sleep_nested().boxed().stack_trace("sleep nested"),
-                multi_sleep().boxed().stack_trace("multi sleep"),
-            ])
-            .await;
-
-            // Join another
-            join_all([
-                sleep(1200).stack_trace("sleep 1200"),
-                sleep(2200).stack_trace("sleep 2200"),
-            ])
-            .await;
-
-            // Cancel
-            select_all([
-                sleep(666).boxed().stack_trace("sleep 666"),
-                sleep_nested()
-                    .boxed()
-                    .stack_trace("sleep nested (should be cancelled)"),
-            ])
-            .await;
-
-            // Nested cancel
-            select_all([
-                sleep(66666)
-                    .boxed()
-                    .stack_trace("sleep 66666 (should be cancelled)"),
-                sleep_nested_select()
-                    .boxed()
-                    .stack_trace("sleep nested select"),
-            ])
-            .await;
-
-            // Join select (nested cancel)
-            join_all([
-                sleep(3000).boxed().stack_trace("sleep 3000"),
-                sleep_nested_select()
-                    .boxed()
-                    .stack_trace("sleep nested select"),
-            ])
-            .await;
-
-            // Check whether cleaned up
-            sleep(233).stack_trace("sleep 233").await;
-
-            // Check stream next drop
-            {
-                let mut stream1 = stream1().fuse().boxed();
-                let mut stream2 = stream2().fuse().boxed();
-                let mut count = 0;
-
-                'outer: loop {
-                    tokio::select! {
-                        _ = stream1.next().stack_trace(format!("stream1 next {count}")) => {},
-                        r = stream2.next().stack_trace(format!("stream2 next {count}")) => {
-                            if r.is_none() { break 'outer }
-                        },
-                    }
-                    count += 1;
-                }
-            }
-
-            // Check whether cleaned up
-            sleep(233).stack_trace("sleep 233").await;
-        }
-        .stack_trace("hello")
-        .await
-    }
+    stack_trace_scenarios!();
 
     #[tokio::test]
     async fn test_stack_trace() {
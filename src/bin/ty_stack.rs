@@ -1,75 +1,143 @@
-use std::cell::RefCell;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 
+use tokio::task::{futures::TaskLocalFuture, JoinHandle};
+
 tokio::task_local! {
     static TRACE: TraceContext;
 }
 
+type Stack = Arc<Mutex<Vec<&'static str>>>;
+type Branches = Arc<Mutex<Vec<Weak<Mutex<Vec<&'static str>>>>>>;
+
+/// The stack of a concurrent branch, i.e., a task or a future wrapped with [`branch`]. The guards
+/// entered in different branches never interleave on the same stack, so they can be held across
+/// awaits.
 #[derive(Debug, Clone)]
 pub struct TraceContext {
-    // SAFETY: We never export &stack or &mut stack, and no await point in all public methods.
-    stack: RefCell<Vec<&'static str>>,
+    stack: Stack,
+    /// The stacks of all branches forked from the same root, for the monitor to collect.
+    branches: Branches,
 }
 
-pub struct TraceGuard;
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::with_stack(Default::default(), Default::default())
+    }
+}
+
+/// Pops the stack of the branch where it's entered to where it's entered on drop, even if dropped
+/// in another branch.
+#[must_use]
+pub struct TraceGuard {
+    stack: Stack,
+    depth: usize,
+}
 
 impl TraceContext {
-    #[must_use]
+    fn with_stack(stack: Vec<&'static str>, branches: Branches) -> Self {
+        let stack = Arc::new(Mutex::new(stack));
+        let mut all = branches.lock().unwrap();
+        // Prune the finished branches, as `collect_all` may never be called.
+        all.retain(|stack| stack.strong_count() > 0);
+        all.push(Arc::downgrade(&stack));
+        drop(all);
+        Self { stack, branches }
+    }
+
+    /// Create a new branch with a snapshot of this stack.
+    fn fork(&self) -> Self {
+        Self::with_stack(self.collect(), self.branches.clone())
+    }
+
     pub fn enter(&self, context: &'static str) -> TraceGuard {
-        self.stack.borrow_mut().push(context);
-        TraceGuard
+        let mut stack = self.stack.lock().unwrap();
+        stack.push(context);
+        TraceGuard {
+            stack: self.stack.clone(),
+            depth: stack.len() - 1,
+        }
     }
 
     pub fn collect(&self) -> Vec<&'static str> {
-        self.stack.borrow().clone()
+        self.stack.lock().unwrap().clone()
+    }
+
+    /// Collect the stacks of all alive branches forked from the same root.
+    pub fn collect_all(&self) -> Vec<Vec<&'static str>> {
+        let mut branches = self.branches.lock().unwrap();
+        branches.retain(|stack| stack.strong_count() > 0);
+        branches
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|stack| stack.lock().unwrap().clone())
+            .collect()
     }
 }
 
 impl Drop for TraceGuard {
     fn drop(&mut self) {
-        TRACE.with(|context| {
-            context.stack.borrow_mut().pop();
-        });
+        // Truncate instead of pop, so that the guards dropped out of order won't mess up the stack.
+        self.stack.lock().unwrap().truncate(self.depth);
     }
 }
 
+/// Push the context to the stack of the current branch, until the guard is dropped. Does nothing
+/// if not in a traced context.
+pub fn enter(context: &'static str) -> Option<TraceGuard> {
+    TRACE.try_with(|c| c.enter(context)).ok()
+}
+
+/// Run the future in a new branch with a snapshot of the current stack, so that it can be polled
+/// concurrently with others, e.g., in `tokio::join!`. A new root is created if not in a traced
+/// context.
+pub fn branch<F: Future>(f: F) -> TaskLocalFuture<TraceContext, F> {
+    let context = TRACE.try_with(TraceContext::fork).unwrap_or_default();
+    TRACE.scope(context, f)
+}
+
+/// Spawn the future as a new task, which inherits the current stack as a new branch.
+pub fn spawn<F>(f: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(branch(f))
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() {
     TRACE
-        .scope(
-            TraceContext {
-                stack: RefCell::new(vec![]),
-            },
-            async move {
-                // The monitor loop, we can check runtime flag here and report the stack.
-                let monitor = async move {
-                    println!("Start monitor!");
-                    let mut i = 8;
-                    while i > 0 {
-                        i -= 1;
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        println!("What are you doing?");
-                        let stack = TRACE.with(|context| context.collect());
-                        println!("{:?}", stack);
-                    }
-                };
-                let task = outer();
-                tokio::join!(monitor, task);
-            },
-        )
+        .scope(TraceContext::default(), async move {
+            // The monitor loop, we can check runtime flag here and report the stack.
+            let monitor = async move {
+                println!("Start monitor!");
+                let mut i = 8;
+                while i > 0 {
+                    i -= 1;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    println!("What are you doing?");
+                    let stacks = TRACE.with(|context| context.collect_all());
+                    println!("{:?}", stacks);
+                }
+            };
+            let task = branch(outer());
+            tokio::join!(monitor, task);
+        })
         .await;
 }
 
 async fn outer() {
-    let _guard = TRACE.with(|context| context.enter("outer"));
+    let _guard = enter("outer");
 
-    tokio::spawn(inner()).await.unwrap();
+    spawn(inner()).await.unwrap();
 
     tokio::time::sleep(Duration::from_secs(2)).await;
 }
 
 async fn inner() {
-    let _guard = TRACE.with(|context| context.enter("inner"));
+    let _guard = enter("inner");
 
     let mut i = 5;
     while i > 0 {
@@ -78,3 +146,75 @@ async fn inner() {
         println!("I'm touching fish!");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current() -> Vec<&'static str> {
+        TRACE.with(TraceContext::collect)
+    }
+
+    #[tokio::test]
+    async fn test_spawn_inherits_stack() {
+        branch(async {
+            let _guard = enter("outer");
+            let handle = spawn(async {
+                let _guard = enter("inner");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                current()
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(current(), ["outer"]);
+            assert_eq!(
+                TRACE.with(TraceContext::collect_all),
+                [vec!["outer"], vec!["outer", "inner"]]
+            );
+            assert_eq!(handle.await.unwrap(), ["outer", "inner"]);
+            assert_eq!(TRACE.with(TraceContext::collect_all), [vec!["outer"]]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_join_branches() {
+        let work = |name, time| {
+            branch(async move {
+                let _guard = enter(name);
+                tokio::time::sleep(Duration::from_millis(time)).await;
+                let stack = current();
+                // The guard is dropped after the other branch has entered.
+                tokio::time::sleep(Duration::from_millis(time)).await;
+                stack
+            })
+        };
+
+        branch(async {
+            let _guard = enter("join");
+            let (a, b) = tokio::join!(work("a", 50), work("b", 80));
+            assert_eq!(a, ["join", "a"]);
+            assert_eq!(b, ["join", "b"]);
+            assert_eq!(current(), ["join"]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_guard_dropped_in_another_branch() {
+        branch(async {
+            let _guard = enter("outer");
+            let guard = branch(async { enter("inner") }).await;
+            let _after = enter("after");
+            // Only pops the stack of the branch where it's entered.
+            drop(guard);
+            assert_eq!(current(), ["outer", "after"]);
+
+            for _ in 0..10 {
+                branch(async {}).await;
+            }
+            // The finished branches are pruned.
+            assert_eq!(TRACE.with(|c| c.branches.lock().unwrap().len()), 2);
+        })
+        .await;
+    }
+}
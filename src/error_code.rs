@@ -0,0 +1,114 @@
+//! Stable numeric codes of the error variants exposed to the clients, declared with `#[code(..)]`
+//! on the variants and registered by the derives of `boxed_error_derive`, which expect this module
+//! at `crate::error_code`.
+
+use std::collections::HashMap;
+use std::error::{Error, Request};
+use std::fmt;
+
+/// The category of an [`ErrorCode`], for the clients to decide how to handle the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(dead_code)] // Not every bin including this module declares codes of all the categories.
+pub enum Category {
+    /// A bug or an unexpected state of the system.
+    Internal,
+    /// The input from the user is invalid.
+    InvalidInput,
+    /// Failed to read or write the storage.
+    Storage,
+    /// Failed in the streaming execution.
+    Stream,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Internal => "internal",
+            Category::InvalidInput => "invalid_input",
+            Category::Storage => "storage",
+            Category::Stream => "stream",
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The stable, machine-readable code of an error variant, declared with `#[code(..)]` and
+/// provided through [`Error::provide`].
+#[derive(Debug)]
+pub struct ErrorCode {
+    pub code: u32,
+    pub category: Category,
+    /// Whether the operation may succeed if retried.
+    pub retryable: bool,
+    /// The path of the variant, like `HummockErrorInner::MagicMismatch`.
+    pub name: &'static str,
+    /// Whether the error is of the variant.
+    #[doc(hidden)]
+    pub matches: fn(&(dyn Error + 'static)) -> bool,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "E{:04}", self.code)
+    }
+}
+
+inventory::collect!(ErrorCode);
+
+/// All the registered codes.
+pub fn registry() -> impl Iterator<Item = &'static ErrorCode> {
+    inventory::iter::<ErrorCode>.into_iter()
+}
+
+/// Get the code registered for the variant of the error, without looking into its sources.
+pub fn of(error: &(dyn Error + 'static)) -> Option<&'static ErrorCode> {
+    registry().find(|code| (code.matches)(error))
+}
+
+/// Provide the code registered for the variant of the error, if any. Call this in
+/// [`Error::provide`] before forwarding to the sources, so that the outermost code wins.
+pub fn provide<'a>(error: &'a (dyn Error + 'static), request: &mut Request<'a>) {
+    // Skip looking up the registry for the requests of other types, e.g., the backtrace.
+    if !request.would_be_satisfied_by_ref_of::<ErrorCode>() {
+        return;
+    }
+    if let Some(code) = of(error) {
+        request.provide_ref::<ErrorCode>(code);
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("error code {code} is registered for both `{first}` and `{second}`")]
+pub struct DuplicateErrorCode {
+    pub code: u32,
+    pub first: &'static str,
+    pub second: &'static str,
+}
+
+/// Check that the codes are unique.
+pub fn check_unique<'a>(
+    codes: impl IntoIterator<Item = &'a ErrorCode>,
+) -> Result<(), DuplicateErrorCode> {
+    let mut seen = HashMap::new();
+    for code in codes {
+        if let Some(first) = seen.insert(code.code, code.name) {
+            return Err(DuplicateErrorCode {
+                code: code.code,
+                first,
+                second: code.name,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check that the registered codes are unique, which should be called at startup since the codes
+/// are exposed to the clients and must be stable.
+pub fn check_registry() -> Result<(), DuplicateErrorCode> {
+    check_unique(registry())
+}
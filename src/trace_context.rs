@@ -0,0 +1,322 @@
+//! The span tree operations that a stack trace backend implements, and the scenarios to test
+//! every backend against the same expected reports.
+
+use std::borrow::Cow;
+use std::fmt::{Debug, Display};
+
+pub type SpanValue = Cow<'static, str>;
+
+/// The span tree of a stack trace context, where the spans of the traced futures are pushed and
+/// popped as they're polled. Implemented by both backends, so that they can be tested with the same
+/// scenarios and compared in benchmarks.
+///
+/// The [`Display`] implementation renders the tree with each span on a line, indented by its depth
+/// and followed by the elapsed time, and the children sorted by span.
+pub trait TraceContext: Display {
+    /// The handle of a span node in the tree.
+    type Node: Clone + PartialEq + Debug;
+
+    /// Create a new context with the given root span.
+    fn new(root_span: SpanValue) -> Self;
+
+    /// Get the current span.
+    fn current(&self) -> Self::Node;
+
+    /// Push a new span as a child of current span. Returns the new current span.
+    fn push(&mut self, span: SpanValue) -> Self::Node;
+
+    /// Step in the current span to the given child.
+    ///
+    /// If the child is not actually a child of the current span, it means we are using a new future
+    /// to poll it, so it will be moved from the previous parent to the current span.
+    fn step_in(&mut self, child: &Self::Node);
+
+    /// Pop the current span to the parent, used when the future is ready.
+    fn pop(&mut self);
+
+    /// Step out the current span to the parent, used when the future is pending.
+    fn step_out(&mut self);
+
+    /// Remove the given span, used when the future is cancelled. The children still alive might be
+    /// polled again later, and will be added as the children of a new span.
+    fn remove_and_detach(&mut self, node: &Self::Node);
+
+    /// Get the count of active span nodes in this context.
+    fn active_node_count(&self) -> usize;
+}
+
+/// Define the test scenarios shared by the backends, in the `tests` module of each backend.
+///
+/// The following items are expected in scope:
+/// - `StackTrace` for tracing the futures,
+/// - `with_context` for accessing the [`TraceContext`] in the scope,
+/// - `async fn run(f)` for running the future `f` with a new context of the root span `root`.
+#[cfg(all(test, feature = "stack-trace"))]
+macro_rules! stack_trace_scenarios {
+    () => {
+        use futures::future::{join_all, select_all};
+        use futures::StreamExt;
+        use futures_async_stream::stream;
+
+        async fn sleep(time: u64) {
+            tokio::time::sleep(std::time::Duration::from_millis(time)).await;
+            println!("slept {time}ms");
+        }
+
+        async fn sleep_nested() {
+            join_all([
+                sleep(1500).stack_trace("sleep nested 1500"),
+                sleep(2500).stack_trace("sleep nested 2500"),
+            ])
+            .await;
+        }
+
+        async fn sleep_nested_select() {
+            select_all([
+                sleep(1500).boxed().stack_trace("sleep nested select 1500"),
+                sleep(2500)
+                    .boxed()
+                    .stack_trace("sleep nested select 2500 (should be cancelled)"),
+            ])
+            .await;
+        }
+
+        async fn multi_sleep() {
+            sleep(400).await;
+
+            sleep(800).stack_trace("sleep another in multi sleep").await;
+        }
+
+        #[stream(item = ())]
+        async fn stream1() {
+            loop {
+                sleep(150).await;
+                yield;
+            }
+        }
+
+        #[stream(item = ())]
+        async fn stream2() {
+            sleep(200).await;
+            yield;
+            join_all([
+                sleep(400).stack_trace("sleep nested 400"),
+                sleep(600).stack_trace("sleep nested 600"),
+            ])
+            .stack_trace("sleep nested another in stream 2")
+            .await;
+            yield;
+        }
+
+        /// Get the lines of the report without the elapsed time.
+        fn report_lines(report: &str) -> Vec<&str> {
+            report
+                .lines()
+                .map(|l| l.rsplit_once(" [").map_or(l, |(l, _)| l))
+                .collect()
+        }
+
+        /// Get the lines of the current report without the elapsed time.
+        fn current_report_lines() -> Vec<String> {
+            let report = with_context(|c| c.to_string());
+            report_lines(&report)
+                .into_iter()
+                .map(str::to_owned)
+                .collect()
+        }
+
+        /// Assert that the futures have been cleaned up, and there's only a single active node of
+        /// root.
+        fn assert_cleaned_up() {
+            assert_eq!(with_context(|c| c.active_node_count()), 1);
+            assert_eq!(current_report_lines(), ["root"]);
+        }
+
+        async fn hello() {
+            async move {
+                // Join
+                join_all([
+                    sleep(1000).boxed().stack_trace(format!("sleep {}", 1000)),
+                    sleep(2000).boxed().stack_trace("sleep 2000"),
+                    sleep_nested().boxed().stack_trace("sleep nested"),
+                    multi_sleep().boxed().stack_trace("multi sleep"),
+                ])
+                .await;
+
+                // Join another
+                join_all([
+                    sleep(1200).stack_trace("sleep 1200"),
+                    sleep(2200).stack_trace("sleep 2200"),
+                ])
+                .await;
+
+                // Cancel
+                select_all([
+                    sleep(666).boxed().stack_trace("sleep 666"),
+                    sleep_nested()
+                        .boxed()
+                        .stack_trace("sleep nested (should be cancelled)"),
+                ])
+                .await;
+
+                // Nested cancel
+                select_all([
+                    sleep(66666)
+                        .boxed()
+                        .stack_trace("sleep 66666 (should be cancelled)"),
+                    sleep_nested_select()
+                        .boxed()
+                        .stack_trace("sleep nested select"),
+                ])
+                .await;
+
+                // Join select (nested cancel)
+                join_all([
+                    sleep(3000).boxed().stack_trace("sleep 3000"),
+                    sleep_nested_select()
+                        .boxed()
+                        .stack_trace("sleep nested select"),
+                ])
+                .await;
+
+                // Check whether cleaned up
+                sleep(233).stack_trace("sleep 233").await;
+
+                // Check stream next drop
+                {
+                    let mut stream1 = stream1().fuse().boxed();
+                    let mut stream2 = stream2().fuse().boxed();
+                    let mut count = 0;
+
+                    'outer: loop {
+                        tokio::select! {
+                            _ = stream1.next().stack_trace(format!("stream1 next {count}")) => {},
+                            r = stream2.next().stack_trace(format!("stream2 next {count}")) => {
+                                if r.is_none() { break 'outer }
+                            },
+                        }
+                        count += 1;
+                    }
+                }
+
+                // Check whether cleaned up
+                sleep(233).stack_trace("sleep 233").await;
+            }
+            .stack_trace("hello")
+            .await;
+
+            // Aborted futures have been cleaned up.
+            assert_eq!(with_context(|c| c.active_node_count()), 1);
+        }
+
+        #[tokio::test]
+        async fn test_scenario_join() {
+            run(async {
+                let work = join_all([
+                    sleep(100).boxed().stack_trace("sleep 100"),
+                    async {
+                        sleep(50).await;
+                        sleep(100).stack_trace("sleep inner 100").await;
+                    }
+                    .boxed()
+                    .stack_trace("sleep nested"),
+                ])
+                .stack_trace("join");
+                let check = async {
+                    sleep(75).await;
+                    assert_eq!(
+                        current_report_lines(),
+                        [
+                            "root",
+                            "  join",
+                            "    sleep 100",
+                            "    sleep nested",
+                            "      sleep inner 100"
+                        ]
+                    );
+                };
+                futures::future::join(work, check).await;
+                assert_cleaned_up();
+            })
+            .await;
+        }
+
+        #[tokio::test]
+        async fn test_scenario_cancel() {
+            run(async {
+                let work = select_all([
+                    sleep(100).boxed().stack_trace("sleep 100"),
+                    async {
+                        join_all([
+                            sleep(1000).boxed().stack_trace("sleep inner 1000"),
+                            sleep_nested_select()
+                                .boxed()
+                                .stack_trace("sleep nested select"),
+                        ])
+                        .await;
+                    }
+                    .boxed()
+                    .stack_trace("sleep nested (should be cancelled)"),
+                ])
+                .stack_trace("select");
+                let check = async {
+                    sleep(50).await;
+                    assert_eq!(
+                        current_report_lines(),
+                        [
+                            "root",
+                            "  select",
+                            "    sleep 100",
+                            "    sleep nested (should be cancelled)",
+                            "      sleep inner 1000",
+                            "      sleep nested select",
+                            "        sleep nested select 1500",
+                            "        sleep nested select 2500 (should be cancelled)",
+                        ]
+                    );
+                };
+                futures::future::join(work, check).await;
+                assert_cleaned_up();
+            })
+            .await;
+        }
+
+        #[tokio::test]
+        async fn test_scenario_select() {
+            run(async {
+                for i in 0..3 {
+                    tokio::select! {
+                        _ = sleep(50).stack_trace(format!("sleep 50 {i}")) => {},
+                        _ = sleep(1000).stack_trace(format!("sleep 1000 {i} (should be cancelled)")) => {
+                            unreachable!()
+                        },
+                    }
+                }
+                assert_cleaned_up();
+            })
+            .await;
+        }
+
+        #[tokio::test]
+        async fn test_scenario_stream() {
+            run(async {
+                let mut stream1 = stream1().fuse().boxed();
+                let mut stream2 = stream2().fuse().boxed();
+                let mut count = 0;
+
+                'outer: loop {
+                    tokio::select! {
+                        _ = stream1.next().stack_trace(format!("stream1 next {count}")) => {},
+                        r = stream2.next().stack_trace(format!("stream2 next {count}")) => {
+                            if r.is_none() { break 'outer }
+                        },
+                    }
+                    count += 1;
+                }
+                drop((stream1, stream2));
+                assert_cleaned_up();
+            })
+            .await;
+        }
+    };
+}